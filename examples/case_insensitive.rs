@@ -0,0 +1,66 @@
+//! Custom symbol equality through a newtype: `Vocab` and `Tokenizer` only
+//! compare symbols with `Ord`, `Eq` and `Hash`, so a wrapper that ignores case
+//! in those impls makes training and tokenizing case-insensitive.
+
+use std::{
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+};
+
+use bpe::Vocab;
+
+#[derive(Debug, Clone, Copy)]
+struct CaseInsensitiveChar(char);
+
+impl CaseInsensitiveChar {
+    fn key(&self) -> char {
+        self.0.to_lowercase().next().unwrap_or(self.0)
+    }
+}
+
+impl PartialEq for CaseInsensitiveChar {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for CaseInsensitiveChar {}
+
+impl PartialOrd for CaseInsensitiveChar {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CaseInsensitiveChar {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl Hash for CaseInsensitiveChar {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+fn symbols(text: &str) -> Vec<CaseInsensitiveChar> {
+    text.chars().map(CaseInsensitiveChar).collect()
+}
+
+fn main() {
+    let mut vocab = Vocab::new(["Token", "TOKEN", "token", "tokens"].map(symbols));
+    vocab.train(10, 2);
+    let tokenizer = vocab.build();
+
+    for text in ["TOKENS", "Tokens", "tOkEn"] {
+        let word = symbols(text);
+        let display = tokenizer
+            .tokenize(&word)
+            .into_iter()
+            .map(|cs| cs.iter().map(|c| c.0).collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!("{} -> {} {:?}", text, display, tokenizer.encode(&word));
+    }
+}
@@ -0,0 +1,589 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::{self, Read, Write},
+};
+
+use crate::{
+    byte_level::{byte_to_bytes, bytes_from_bytes, char_to_bytes, chars_from_bytes},
+    registry::IdRegistry,
+    ByteFallback, Merge, Tokenizer, Vocab,
+};
+
+const MAGIC: &[u8; 4] = b"BPE\0";
+const VERSION: u8 = 3;
+const VOCAB_MAGIC: &[u8; 4] = b"BPV\0";
+const VOCAB_VERSION: u8 = 2;
+const REGISTRY_MAGIC: &[u8; 4] = b"BPR\0";
+const REGISTRY_VERSION: u8 = 1;
+
+/// Symbols that can be stored in the binary tokenizer format.
+pub trait BinSymbol: Sized {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self>;
+
+    /// The conversions to and from bytes that byte fallback uses, for
+    /// symbols that support it.
+    #[allow(clippy::type_complexity)]
+    fn byte_conversions() -> Option<(fn(&Self) -> Vec<u8>, fn(&[u8]) -> Option<Vec<Self>>)> {
+        None
+    }
+}
+
+impl BinSymbol for u8 {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&[*self])
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut buf = [0; 1];
+        r.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn byte_conversions() -> Option<(fn(&Self) -> Vec<u8>, fn(&[u8]) -> Option<Vec<Self>>)> {
+        Some((byte_to_bytes, bytes_from_bytes))
+    }
+}
+
+impl BinSymbol for u16 {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut buf = [0; 2];
+        r.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+}
+
+impl BinSymbol for u32 {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+}
+
+impl BinSymbol for char {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u32).write_to(w)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        char::from_u32(u32::read_from(r)?).ok_or_else(|| invalid("invalid char"))
+    }
+
+    fn byte_conversions() -> Option<(fn(&Self) -> Vec<u8>, fn(&[u8]) -> Option<Vec<Self>>)> {
+        Some((char_to_bytes, chars_from_bytes))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_len<W: Write>(w: &mut W, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid("length does not fit in u32"))?;
+    len.write_to(w)
+}
+
+fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    Ok(u32::read_from(r)? as usize)
+}
+
+/// Counts are stored as `u64`s, since weighted corpora can exceed `u32`.
+fn write_count<W: Write>(w: &mut W, count: usize) -> io::Result<()> {
+    w.write_all(&(count as u64).to_le_bytes())
+}
+
+fn read_count<R: Read>(r: &mut R) -> io::Result<usize> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    usize::try_from(u64::from_le_bytes(buf)).map_err(|_| invalid("count does not fit in usize"))
+}
+
+fn write_symbols<C: BinSymbol, W: Write>(w: &mut W, symbols: &[C]) -> io::Result<()> {
+    write_len(w, symbols.len())?;
+    for c in symbols {
+        c.write_to(w)?;
+    }
+    Ok(())
+}
+
+fn read_symbols<C: BinSymbol, R: Read>(r: &mut R) -> io::Result<Vec<C>> {
+    (0..read_len(r)?).map(|_| C::read_from(r)).collect()
+}
+
+fn write_specials<W: Write>(w: &mut W, specials: &[String]) -> io::Result<()> {
+    write_len(w, specials.len())?;
+    for special in specials {
+        write_len(w, special.len())?;
+        w.write_all(special.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_specials<R: Read>(r: &mut R) -> io::Result<Vec<String>> {
+    let mut specials = Vec::new();
+    for _ in 0..read_len(r)? {
+        // The length is untrusted, so the buffer grows only as bytes arrive.
+        let len = read_len(r)?;
+        let mut buf = Vec::new();
+        r.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        specials.push(String::from_utf8(buf).map_err(|_| invalid("invalid special token"))?);
+    }
+    Ok(specials)
+}
+
+/// Checks the magic number and returns the version, which can be anything
+/// from 1 to `version`.
+fn read_header<R: Read>(r: &mut R, magic: &[u8; 4], version: u8) -> io::Result<u8> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    if &buf != magic {
+        return Err(invalid("not a tokenizer file"));
+    }
+    let found = u8::read_from(r)?;
+    if !(1..=version).contains(&found) {
+        return Err(invalid("unsupported tokenizer file version"));
+    }
+    Ok(found)
+}
+
+fn write_option<C: BinSymbol, W: Write>(w: &mut W, x: Option<&C>) -> io::Result<()> {
+    match x {
+        Some(x) => {
+            w.write_all(&[1])?;
+            x.write_to(w)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+fn read_option<C: BinSymbol, R: Read>(r: &mut R) -> io::Result<Option<C>> {
+    match u8::read_from(r)? {
+        0 => Ok(None),
+        1 => C::read_from(r).map(Some),
+        _ => Err(invalid("invalid flag")),
+    }
+}
+
+/// The format is a magic number and version byte, then the special tokens
+/// as length-prefixed UTF-8 strings, the unknown id, and the learned tokens
+/// as length-prefixed symbol sequences in id order, with an unused id stored
+/// as an empty token. Each token is followed by its merge frequency, or 0 if
+/// it has none, and the length of the left half of its merge, or 0. Then
+/// come the protected tokens, a flag byte and, if set, the end-of-word
+/// marker, a flag byte for merge ranks, and a flag byte and, if set, the
+/// first byte fallback id. Frequencies are little endian `u64`s and all
+/// other integers little endian `u32`s. Version 2 files, with `u32`
+/// frequencies, and version 1 files, which also end after the frequencies,
+/// can still be read.
+impl<C: Ord + Hash + Clone + BinSymbol> Tokenizer<C> {
+    /// Fails with `InvalidInput` for a tokenizer with a normalizer, which
+    /// cannot be stored.
+    pub fn write_bin<W: Write>(&self, mut w: W) -> io::Result<()> {
+        if self.normalizer.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a normalizer cannot be stored",
+            ));
+        }
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+
+        write_specials(&mut w, &self.specials)?;
+        write_len(&mut w, self.unk_id)?;
+
+        write_len(&mut w, self.tokens.len())?;
+        for token in &self.tokens {
+            let token = token.as_deref().unwrap_or_default();
+            write_symbols(&mut w, token)?;
+            write_count(&mut w, self.frequencies.get(token).copied().unwrap_or(0))?;
+            write_len(&mut w, self.splits.get(token).copied().unwrap_or(0))?;
+        }
+
+        write_len(&mut w, self.protected.len())?;
+        for token in &self.protected {
+            write_symbols(&mut w, token)?;
+        }
+        write_option(&mut w, self.end_of_word.as_ref())?;
+        w.write_all(&[self.merge_ranked as u8])?;
+        let first_id = self.byte_fallback.map(|x| x.first_id as u32);
+        write_option(&mut w, first_id.as_ref())
+    }
+
+    pub fn read_bin<R: Read>(mut r: R) -> io::Result<Self> {
+        let version = read_header(&mut r, MAGIC, VERSION)?;
+
+        let specials = read_specials(&mut r)?;
+        let unk_id = read_len(&mut r)?;
+        if unk_id >= specials.len() {
+            return Err(invalid("unknown id is not a special token"));
+        }
+
+        let mut tokens = Vec::new();
+        let mut frequencies = HashMap::new();
+        let mut splits = HashMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let token = read_symbols::<C, _>(&mut r)?;
+            let frequency = if version >= 3 {
+                read_count(&mut r)?
+            } else {
+                read_len(&mut r)?
+            };
+            let split = if version >= 2 { read_len(&mut r)? } else { 0 };
+            if split >= token.len().max(1) {
+                return Err(invalid("invalid merge split"));
+            }
+            if !token.is_empty() && frequency > 0 {
+                frequencies.insert(token.clone(), frequency);
+            }
+            if split > 0 {
+                splits.insert(token.clone(), split);
+            }
+            tokens.push((!token.is_empty()).then_some(token));
+        }
+
+        let mut tokenizer = Self::from_parts(specials, unk_id, tokens);
+        tokenizer.frequencies = frequencies;
+        tokenizer.splits = splits;
+        if version == 1 {
+            return Ok(tokenizer);
+        }
+
+        let protected = (0..read_len(&mut r)?)
+            .map(|_| read_symbols(&mut r))
+            .collect::<io::Result<_>>()?;
+        tokenizer.with_protected(protected);
+        tokenizer.end_of_word = read_option(&mut r)?;
+        tokenizer.merge_ranked = match u8::read_from(&mut r)? {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid("invalid flag")),
+        };
+        if let Some(first_id) = read_option::<u32, _>(&mut r)? {
+            let first_id = first_id as usize;
+            if first_id + 256 > tokenizer.specials.len() {
+                return Err(invalid("byte fallback ids are not special tokens"));
+            }
+            let (to_bytes, from_bytes) =
+                C::byte_conversions().ok_or_else(|| invalid("byte fallback is not supported"))?;
+            tokenizer.byte_fallback = Some(ByteFallback {
+                first_id,
+                to_bytes,
+                from_bytes,
+            });
+        }
+        Ok(tokenizer)
+    }
+}
+
+/// This binary form is the stable on-disk format; there is no JSON form,
+/// since the crate does not depend on serde. Byte-level tokenizers can be
+/// exported as JSON for other tools with `Tokenizer::export_huggingface`.
+///
+/// The vocabulary format has its own magic number and version byte, then
+/// the tokens in id order and the merges in the order they were learned,
+/// each as the token followed by the length of its left half and its
+/// frequency, a `u64` (a `u32` in version 1, which can still be read). Last
+/// comes a flag byte and, if set, the end-of-word marker. The corpus is not
+/// stored.
+impl<C: Ord + Hash + Clone + BinSymbol> Vocab<C> {
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(VOCAB_MAGIC)?;
+        w.write_all(&[VOCAB_VERSION])?;
+
+        write_len(&mut w, self.order.len())?;
+        for token in &self.order {
+            write_symbols(&mut w, token)?;
+        }
+        write_len(&mut w, self.merges.len())?;
+        for merge in &self.merges {
+            write_symbols(&mut w, &merge.token)?;
+            write_len(&mut w, merge.split)?;
+            write_count(&mut w, merge.frequency)?;
+        }
+        write_option(&mut w, self.end_of_word.as_ref())
+    }
+
+    /// Reads a vocabulary written by `save`. It has an empty corpus, so it
+    /// can be built or given seed tokens, but further merges find nothing.
+    pub fn load<R: Read>(mut r: R) -> io::Result<Self> {
+        let version = read_header(&mut r, VOCAB_MAGIC, VOCAB_VERSION)?;
+
+        let mut vocab = Self::new(std::iter::empty::<Vec<C>>());
+        for _ in 0..read_len(&mut r)? {
+            let token = read_symbols(&mut r)?;
+            if token.is_empty() {
+                return Err(invalid("empty token"));
+            }
+            vocab.insert_token(token);
+        }
+        for _ in 0..read_len(&mut r)? {
+            let token = read_symbols::<C, _>(&mut r)?;
+            let split = read_len(&mut r)?;
+            let frequency = if version >= 2 {
+                read_count(&mut r)?
+            } else {
+                read_len(&mut r)?
+            };
+            if split == 0 || split >= token.len() || !vocab.tokens.contains(&token) {
+                return Err(invalid("invalid merge"));
+            }
+            vocab.merges.push(Merge {
+                token,
+                split,
+                frequency,
+            });
+        }
+        vocab.end_of_word = read_option(&mut r)?;
+        Ok(vocab)
+    }
+}
+
+/// The registry format has its own magic number and version byte, then the
+/// special tokens as in the tokenizer format and the registered tokens in
+/// id order.
+impl<C: Ord + Hash + Clone + BinSymbol> IdRegistry<C> {
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(REGISTRY_MAGIC)?;
+        w.write_all(&[REGISTRY_VERSION])?;
+
+        write_specials(&mut w, &self.specials)?;
+        write_len(&mut w, self.tokens.len())?;
+        for token in &self.tokens {
+            write_symbols(&mut w, token)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a registry written by `save`, with every id as it was.
+    pub fn load<R: Read>(mut r: R) -> io::Result<Self> {
+        read_header(&mut r, REGISTRY_MAGIC, REGISTRY_VERSION)?;
+
+        let specials = read_specials(&mut r)?;
+        let mut tokens = Vec::new();
+        let mut ids = HashMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let token = read_symbols::<C, _>(&mut r)?;
+            let id = specials.len() + tokens.len();
+            if token.is_empty() || ids.insert(token.clone(), id).is_some() {
+                return Err(invalid("invalid registered token"));
+            }
+            tokens.push(token);
+        }
+        Ok(Self {
+            specials,
+            tokens,
+            ids,
+        })
+    }
+}
+
+impl<C: Ord + Hash + Clone + BinSymbol> Tokenizer<C> {
+    /// Builds the tokenizer of a vocabulary written by `Vocab::save`.
+    pub fn from_vocab_reader<R: Read>(r: R) -> io::Result<Self> {
+        Vocab::load(r).map(|x| x.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::data_words, train, Vocab};
+
+    use super::*;
+
+    #[test]
+    fn bin_round_trip() {
+        let words = ["banana", "bandana", "ananas", "cabana", "naïve"]
+            .map(|w| w.chars().collect::<Vec<_>>());
+        let tokenizer = train(words.clone(), 10, 1);
+
+        let mut buf = Vec::new();
+        tokenizer.write_bin(&mut buf).unwrap();
+        let loaded = Tokenizer::<char>::read_bin(buf.as_slice()).unwrap();
+
+        assert_eq!(loaded.vocab_size(), tokenizer.vocab_size());
+        for id in 0..tokenizer.vocab_size() {
+            assert_eq!(loaded.id_to_token(id), tokenizer.id_to_token(id));
+        }
+        for word in &words {
+            assert_eq!(loaded.encode(word), tokenizer.encode(word));
+            assert_eq!(
+                loaded.tokenize_freq_preferred(word, 1.0),
+                tokenizer.tokenize_freq_preferred(word, 1.0)
+            );
+        }
+
+        assert!(Tokenizer::<char>::read_bin(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bin_keeps_settings() {
+        let words = data_words();
+        let mut vocab = Vocab::with_end_of_word(words.clone(), '$');
+        vocab.train(100, 2);
+        let mut tokenizer = vocab.build().with_merge_ranks().with_byte_fallback();
+        tokenizer.with_protected(vec!["<sep>".chars().collect()]);
+
+        let mut buf = Vec::new();
+        tokenizer.write_bin(&mut buf).unwrap();
+        let loaded = Tokenizer::<char>::read_bin(buf.as_slice()).unwrap();
+        assert_eq!(loaded.end_of_word(), Some(&'$'));
+        let mut input = words.clone();
+        input.push("🍌<sep>x".chars().collect());
+        assert_eq!(loaded.encode_words(&input), tokenizer.encode_words(&input));
+
+        let normalized = train(words, 10, 2).with_normalizer(|c| c.to_ascii_lowercase());
+        let err = normalized.write_bin(Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn bin_rejects_corrupt_input() {
+        let tokenizer = train(data_words(), 50, 2);
+        let mut buf = Vec::new();
+        tokenizer.write_bin(&mut buf).unwrap();
+        for len in 0..buf.len() {
+            assert!(Tokenizer::<char>::read_bin(&buf[..len]).is_err());
+        }
+        assert!(Tokenizer::<char>::read_bin(&b"XYZ\0\x02"[..]).is_err());
+        assert!(Tokenizer::<char>::read_bin(&b"BPE\0\xff"[..]).is_err());
+
+        // A huge special token length fails on the missing bytes instead of
+        // allocating up front.
+        let mut huge = b"BPE\0\x03".to_vec();
+        huge.extend(1u32.to_le_bytes());
+        huge.extend(u32::MAX.to_le_bytes());
+        huge.extend(b"<unk>");
+        let err = Tokenizer::<char>::read_bin(huge.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(IdRegistry::<char>::load(&b"BPR\0\x01\x01\0\0\0\xff\xff\xff\xff"[..]).is_err());
+    }
+
+    #[test]
+    fn bin_reads_version_1() {
+        let mut buf = b"BPE\0\x01".to_vec();
+        for n in [1u32, 5] {
+            buf.extend(n.to_le_bytes());
+        }
+        buf.extend(b"<unk>");
+        for n in [0, 1, 2, 'a' as u32, 'b' as u32, 3] {
+            buf.extend(n.to_le_bytes());
+        }
+        let loaded = Tokenizer::<char>::read_bin(buf.as_slice()).unwrap();
+        assert_eq!(loaded.token_to_id(&['a', 'b']), Some(1));
+        assert_eq!(loaded.frequencies.get(&vec!['a', 'b']), Some(&3));
+
+        // Version 2 adds the split and the settings after each frequency.
+        let mut buf = b"BPE\0\x02".to_vec();
+        for n in [1u32, 5] {
+            buf.extend(n.to_le_bytes());
+        }
+        buf.extend(b"<unk>");
+        for n in [0, 1, 2, 'a' as u32, 'b' as u32, 3, 1, 0] {
+            buf.extend(n.to_le_bytes());
+        }
+        buf.extend([0, 1, 0]);
+        let loaded = Tokenizer::<char>::read_bin(buf.as_slice()).unwrap();
+        assert_eq!(loaded.frequencies.get(&vec!['a', 'b']), Some(&3));
+        assert_eq!(loaded.splits.get(&vec!['a', 'b']), Some(&1));
+        assert!(loaded.merge_ranked);
+    }
+
+    #[test]
+    fn counts_beyond_u32_round_trip() {
+        let count = u32::MAX as u64 * 2;
+        let mut vocab = Vocab::from_counts([("ab".chars(), count)]);
+        vocab.train(1, 1);
+        assert_eq!(vocab.merges[0].frequency as u64, count);
+
+        let mut buf = Vec::new();
+        vocab.build().write_bin(&mut buf).unwrap();
+        let loaded = Tokenizer::<char>::read_bin(buf.as_slice()).unwrap();
+        assert_eq!(loaded.frequencies[&vec!['a', 'b']] as u64, count);
+
+        let mut buf = Vec::new();
+        vocab.save(&mut buf).unwrap();
+        let loaded = Vocab::<char>::load(buf.as_slice()).unwrap();
+        assert_eq!(loaded.merges[0].frequency as u64, count);
+
+        // Version 1 vocabularies stored the frequency as a `u32`.
+        let mut buf = b"BPV\0\x01".to_vec();
+        for n in [
+            1, 2, 'a' as u32, 'b' as u32, 1, 2, 'a' as u32, 'b' as u32, 1, 7,
+        ] {
+            buf.extend(n.to_le_bytes());
+        }
+        buf.push(0);
+        let loaded = Vocab::<char>::load(buf.as_slice()).unwrap();
+        assert_eq!(loaded.merges[0].frequency, 7);
+    }
+
+    #[test]
+    fn vocab_save_load() {
+        let words = ["banana", "bandana", "ananas", "cabana", "naïve"]
+            .map(|w| w.chars().collect::<Vec<_>>());
+        let mut vocab = Vocab::with_end_of_word(words.clone(), '$');
+        vocab.train(10, 1);
+        let vocab = vocab.with_seed_tokens([vec!['z']]);
+
+        let mut buf = Vec::new();
+        vocab.save(&mut buf).unwrap();
+        let loaded = Vocab::<char>::load(buf.as_slice()).unwrap();
+        assert_eq!(loaded.order, vocab.order);
+        assert_eq!(
+            loaded
+                .merge_records()
+                .map(|m| (m.left(), m.right(), m.frequency()))
+                .collect::<Vec<_>>(),
+            vocab
+                .merge_records()
+                .map(|m| (m.left(), m.right(), m.frequency()))
+                .collect::<Vec<_>>()
+        );
+
+        let tokenizer = vocab.build();
+        let rebuilt = Tokenizer::<char>::from_vocab_reader(buf.as_slice()).unwrap();
+        assert_eq!(rebuilt.end_of_word(), Some(&'$'));
+        for word in &words {
+            assert_eq!(
+                rebuilt.encode_words([word.clone()]),
+                tokenizer.encode_words([word.clone()])
+            );
+        }
+
+        assert!(Vocab::<char>::load(&buf[..buf.len() - 1]).is_err());
+        assert!(Tokenizer::<char>::from_vocab_reader(&b"BPE\0\x01"[..]).is_err());
+    }
+
+    #[test]
+    fn registry_save_load() {
+        let words = ["banana", "bandana", "cabana"].map(|w| w.chars().collect::<Vec<_>>());
+        let mut vocab = Vocab::new(words);
+        let mut registry = IdRegistry::new();
+        vocab.train_registered(6, 2, &mut registry);
+
+        let mut buf = Vec::new();
+        registry.save(&mut buf).unwrap();
+        let mut loaded = IdRegistry::<char>::load(buf.as_slice()).unwrap();
+        assert_eq!(loaded.len(), registry.len());
+        for token in &vocab.order {
+            assert_eq!(loaded.id(token), registry.id(token));
+        }
+        let token = "zz".chars().collect::<Vec<_>>();
+        assert_eq!(loaded.register(token.clone()), registry.register(token));
+
+        assert!(IdRegistry::<char>::load(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,572 @@
+use std::{borrow::Cow, string::FromUtf8Error, sync::Arc};
+
+use crate::{
+    text::TextOptions, ByteFallback, DecodeError, MergeError, MergeStep, Tokenizer, Vocab,
+};
+
+pub struct ByteVocab {
+    vocab: Vocab<u8>,
+}
+
+pub struct ByteTokenizer {
+    tokenizer: Tokenizer<u8>,
+}
+
+/// Splits text into the words a vocabulary is trained on and input is
+/// tokenized in. Tokens never span two words. Any closure works, so a
+/// compiled regex can be captured, but words must cover the text for
+/// decoding to restore it.
+pub type PreTokenizer = Arc<dyn Fn(&str) -> Vec<&str> + Send + Sync>;
+
+/// Byte-level BPE as in GPT-2: text is split with a `PreTokenizer` and each
+/// word's UTF-8 bytes are mapped to characters with `byte_to_unicode`, so
+/// every input can be tokenized without the unknown token.
+pub struct ByteLevelVocab {
+    vocab: Vocab<char>,
+    pre_tokenizer: PreTokenizer,
+}
+
+pub struct ByteLevelTokenizer {
+    tokenizer: Tokenizer<char>,
+    pre_tokenizer: PreTokenizer,
+}
+
+impl ByteVocab {
+    pub fn new<'a, Words>(words: Words) -> Self
+    where
+        Words: IntoIterator<Item = &'a str>,
+    {
+        Self {
+            vocab: Vocab::new(words.into_iter().map(|w| w.bytes())),
+        }
+    }
+
+    /// Builds a vocabulary from `text` with one word per line.
+    pub fn from_text(text: &str, options: TextOptions) -> Self {
+        let text = options.apply(text);
+        Self::new(text.split('\n'))
+    }
+
+    pub fn merge(&mut self, min_freq: usize) -> Result<MergeStep<u8>, MergeError> {
+        self.vocab.merge(min_freq)
+    }
+
+    pub fn build(&self) -> ByteTokenizer {
+        ByteTokenizer {
+            tokenizer: self.vocab.build(),
+        }
+    }
+
+    pub fn vocab(&self) -> &Vocab<u8> {
+        &self.vocab
+    }
+}
+
+impl ByteTokenizer {
+    /// Tokens are byte slices and may end in the middle of a multi-byte
+    /// character, so they are not necessarily valid UTF-8 on their own.
+    pub fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a [u8]> {
+        self.tokenizer.tokenize(text.as_bytes())
+    }
+
+    /// Concatenates all token bytes before converting, so characters split
+    /// across token boundaries are recombined.
+    pub fn decode(&self, tokens: &[&[u8]]) -> Result<String, FromUtf8Error> {
+        String::from_utf8(tokens.concat())
+    }
+
+    /// Renders each token on its own for display, replacing partial
+    /// characters with U+FFFD.
+    pub fn display<'a>(&self, tokens: &[&'a [u8]]) -> Vec<Cow<'a, str>> {
+        tokens.iter().map(|t| String::from_utf8_lossy(t)).collect()
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer<u8> {
+        &self.tokenizer
+    }
+}
+
+impl Tokenizer<char> {
+    /// Re-encodes every token as UTF-8 bytes, keeping ids, special tokens,
+    /// protected tokens and byte fallback, so uncovered bytes get the same
+    /// `<0xNN>` ids as here. The normalizer is not carried over, and neither
+    /// is an end-of-word marker outside ASCII, which is not a single byte.
+    pub fn to_bytes(&self) -> Tokenizer<u8> {
+        let encode = |x: &Vec<char>| x.iter().collect::<String>().into_bytes();
+        let tokens = self.tokens.iter().map(|x| x.as_ref().map(encode)).collect();
+        let mut result = Tokenizer::from_parts(self.specials.clone(), self.unk_id, tokens);
+        result.with_protected(self.protected.iter().map(encode).collect());
+        result.frequencies = self
+            .frequencies
+            .iter()
+            .map(|(x, n)| (encode(x), *n))
+            .collect();
+        result.splits = self
+            .splits
+            .iter()
+            .map(|(x, n)| (encode(x), encode(&x[..*n].to_vec()).len()))
+            .collect();
+        result.end_of_word = self.end_of_word.filter(char::is_ascii).map(|c| c as u8);
+        result.merge_ranked = self.merge_ranked;
+        result.byte_fallback = self.byte_fallback.map(|x| ByteFallback {
+            first_id: x.first_id,
+            to_bytes: byte_to_bytes,
+            from_bytes: bytes_from_bytes,
+        });
+        result
+    }
+}
+
+impl Tokenizer<char> {
+    /// Reserves 256 special tokens `<0x00>` to `<0xFF>` after the existing
+    /// ones and encodes every character without a token as the ids of its
+    /// UTF-8 bytes instead of `unk_id`. The ids of learned tokens shift up by
+    /// 256.
+    pub fn with_byte_fallback(self) -> Self {
+        if self.byte_fallback.is_some() {
+            return self;
+        }
+        let first_id = self.specials.len();
+        let mut specials = self.specials.clone();
+        specials.extend((0..=255u8).map(|b| format!("<0x{:02X}>", b)));
+
+        let mut result = self.with_parts(specials, self.tokens.clone());
+        result.byte_fallback = Some(ByteFallback {
+            first_id,
+            to_bytes: char_to_bytes,
+            from_bytes: chars_from_bytes,
+        });
+        result
+    }
+}
+
+pub(crate) fn char_to_bytes(c: &char) -> Vec<u8> {
+    c.to_string().into_bytes()
+}
+
+pub(crate) fn chars_from_bytes(b: &[u8]) -> Option<Vec<char>> {
+    std::str::from_utf8(b).ok().map(|x| x.chars().collect())
+}
+
+pub(crate) fn byte_to_bytes(b: &u8) -> Vec<u8> {
+    vec![*b]
+}
+
+pub(crate) fn bytes_from_bytes(b: &[u8]) -> Option<Vec<u8>> {
+    Some(b.to_vec())
+}
+
+impl Tokenizer<u8> {
+    /// The inverse of `Tokenizer::<char>::to_bytes`, failing if any token or
+    /// the end-of-word marker is not valid UTF-8 on its own.
+    pub fn to_chars(&self) -> Result<Tokenizer<char>, FromUtf8Error> {
+        let decode = |x: &Vec<u8>| String::from_utf8(x.clone()).map(|x| x.chars().collect());
+        let tokens = self
+            .tokens
+            .iter()
+            .map(|x| x.as_ref().map(decode).transpose())
+            .collect::<Result<_, _>>()?;
+        let mut result = Tokenizer::from_parts(self.specials.clone(), self.unk_id, tokens);
+        result.with_protected(
+            self.protected
+                .iter()
+                .map(decode)
+                .collect::<Result<_, _>>()?,
+        );
+        result.frequencies = self
+            .frequencies
+            .iter()
+            .map(|(x, n)| Ok((decode(x)?, *n)))
+            .collect::<Result<_, _>>()?;
+        result.splits = self
+            .splits
+            .iter()
+            .map(|(x, n)| Ok((decode(x)?, decode(&x[..*n].to_vec())?.len())))
+            .collect::<Result<_, _>>()?;
+        result.end_of_word = match self.end_of_word {
+            Some(b) => decode(&vec![b])?.first().copied(),
+            None => None,
+        };
+        result.merge_ranked = self.merge_ranked;
+        result.byte_fallback = self.byte_fallback.map(|x| ByteFallback {
+            first_id: x.first_id,
+            to_bytes: char_to_bytes,
+            from_bytes: chars_from_bytes,
+        });
+        Ok(result)
+    }
+}
+
+/// The GPT-2 mapping of bytes to printable characters: printable Latin-1
+/// bytes map to themselves and the rest, in order, to U+0100 onwards.
+pub fn byte_to_unicode(b: u8) -> char {
+    BYTE_TO_UNICODE[b as usize]
+}
+
+const BYTE_TO_UNICODE: [char; 256] = {
+    let mut table = ['\0'; 256];
+    let mut next = 256;
+    let mut b = 0;
+    while b < 256 {
+        table[b] = match b {
+            0x21..=0x7e | 0xa1..=0xac | 0xae..=0xff => b as u8 as char,
+            _ => {
+                next += 1;
+                char::from_u32(next - 1).unwrap()
+            }
+        };
+        b += 1;
+    }
+    table
+};
+
+/// The byte mapped to each character up to U+0143, the last one used.
+/// Characters no byte maps to hold 0, which `unicode_to_byte` rejects.
+const UNICODE_TO_BYTE: [u8; 324] = {
+    let mut table = [0; 324];
+    let mut b = 0;
+    while b < 256 {
+        table[BYTE_TO_UNICODE[b] as usize] = b as u8;
+        b += 1;
+    }
+    table
+};
+
+/// The inverse of `byte_to_unicode`.
+pub fn unicode_to_byte(c: char) -> Option<u8> {
+    let b = *UNICODE_TO_BYTE.get(c as usize)?;
+    (byte_to_unicode(b) == c).then_some(b)
+}
+
+/// Roughly the GPT-2 pre-tokenizer without its contraction rules: runs of
+/// letters, of digits and of other symbols, each taking one preceding
+/// space, and runs of whitespace, leaving a final space to the next word.
+pub fn split_gpt2(text: &str) -> Vec<&str> {
+    #[derive(PartialEq)]
+    enum Class {
+        Letter,
+        Digit,
+        Space,
+        Other,
+    }
+    let class = |c: char| match c {
+        c if c.is_alphabetic() => Class::Letter,
+        c if c.is_numeric() => Class::Digit,
+        c if c.is_whitespace() => Class::Space,
+        _ => Class::Other,
+    };
+
+    let chars = text.char_indices().collect::<Vec<_>>();
+    let offset = |i: usize| chars.get(i).map_or(text.len(), |x| x.0);
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        if chars[i].1 == ' ' && chars.get(i + 1).is_some_and(|x| !x.1.is_whitespace()) {
+            i += 1;
+        }
+        let run = class(chars[i].1);
+        while i < chars.len() && class(chars[i].1) == run {
+            i += 1;
+        }
+        if run == Class::Space && i < chars.len() && i - start > 1 && chars[i - 1].1 == ' ' {
+            i -= 1;
+        }
+        result.push(&text[offset(start)..offset(i)]);
+    }
+    result
+}
+
+/// Splits before every run of whitespace, so each word but the first starts
+/// with the whitespace preceding it.
+pub fn split_at_whitespace(text: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut prev_space = true;
+    for (i, c) in text.char_indices() {
+        let space = c.is_whitespace();
+        if space && !prev_space {
+            result.push(&text[start..i]);
+            start = i;
+        }
+        prev_space = space;
+    }
+    if start < text.len() {
+        result.push(&text[start..]);
+    }
+    result
+}
+
+fn to_mapped(word: &str) -> Vec<char> {
+    word.bytes().map(byte_to_unicode).collect()
+}
+
+impl ByteLevelVocab {
+    pub fn new(
+        text: &str,
+        pre_tokenizer: impl Fn(&str) -> Vec<&str> + Send + Sync + 'static,
+    ) -> Self {
+        let pre_tokenizer: PreTokenizer = Arc::new(pre_tokenizer);
+        Self {
+            vocab: Vocab::new(pre_tokenizer(text).into_iter().map(to_mapped)),
+            pre_tokenizer,
+        }
+    }
+
+    pub fn train(&mut self, vocab_size: usize, min_freq: usize) {
+        self.vocab.train(vocab_size, min_freq)
+    }
+
+    /// Every byte is a token of its own besides the learned ones.
+    pub fn build(&self) -> ByteLevelTokenizer {
+        let alphabet = (0..=255).map(|b| vec![byte_to_unicode(b)]);
+        let mut order = self.vocab.order.clone();
+        order.extend(alphabet);
+        let tokenizer = Tokenizer::from_tokens(order).unwrap();
+        ByteLevelTokenizer {
+            tokenizer: self.vocab.finish(tokenizer),
+            pre_tokenizer: self.pre_tokenizer.clone(),
+        }
+    }
+
+    pub fn vocab(&self) -> &Vocab<char> {
+        &self.vocab
+    }
+}
+
+impl ByteLevelTokenizer {
+    pub fn encode(&self, text: &str) -> Vec<usize> {
+        (self.pre_tokenizer)(text)
+            .into_iter()
+            .flat_map(|word| self.tokenizer.encode(&to_mapped(word)))
+            .collect()
+    }
+
+    /// Tokens in their mapped form, such as `"Ġworld"` for `" world"`.
+    pub fn tokenize(&self, text: &str) -> Vec<String> {
+        (self.pre_tokenizer)(text)
+            .into_iter()
+            .flat_map(|word| self.tokenizer.tokenize_owned(to_mapped(word)))
+            .map(|token| token.into_iter().collect())
+            .collect()
+    }
+
+    /// Fails on invalid ids, and on UTF-8 errors, which only occur if `ids`
+    /// do not come from `encode`.
+    pub fn decode(&self, ids: &[usize]) -> Result<String, DecodeError> {
+        // Every symbol of a token is a mapped byte.
+        let bytes = self
+            .tokenizer
+            .decode(ids)?
+            .into_iter()
+            .filter_map(unicode_to_byte)
+            .collect::<Vec<_>>();
+        String::from_utf8(bytes).map_err(|e| DecodeError::InvalidBytes(e.into_bytes()))
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer<char> {
+        &self.tokenizer
+    }
+}
+
+impl From<Tokenizer<u8>> for ByteTokenizer {
+    fn from(tokenizer: Tokenizer<u8>) -> Self {
+        Self { tokenizer }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::data_words;
+
+    #[test]
+    fn decode_recombines_split_chars() {
+        // "caf" plus the first byte of "é" is the only pair sequence, so the
+        // learned token ends in the middle of the character.
+        let mut vocab = Vocab::new([b"caf\xc3".to_vec()]);
+        while vocab.merge(1).is_ok() {}
+        let tokenizer = ByteTokenizer::from(vocab.build());
+
+        let text = "café";
+        let tokens = tokenizer.tokenize(text);
+        assert_eq!(tokens, [&b"caf\xc3"[..], &b"\xa9"[..]]);
+        assert!(std::str::from_utf8(tokens[0]).is_err());
+
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), text);
+        assert_eq!(tokenizer.display(&tokens), ["caf\u{fffd}", "\u{fffd}"]);
+
+        assert!(tokenizer.tokenizer().to_chars().is_err());
+    }
+
+    #[test]
+    fn byte_fallback_round_trips() {
+        let words = ["banana", "bandana"].map(|w| w.chars().collect::<Vec<_>>());
+        let plain = crate::train(words.clone(), 10, 1);
+        let tokenizer = crate::train(words, 10, 1).with_byte_fallback();
+        assert_eq!(tokenizer.vocab_size(), plain.vocab_size() + 256);
+
+        let word = "🍌banana".chars().collect::<Vec<_>>();
+        assert!(plain.encode(&word).contains(&plain.unk_id()));
+        let ids = tokenizer.encode(&word);
+        assert!(!ids.contains(&tokenizer.unk_id()));
+        let emoji = "🍌".bytes().map(|b| 1 + b as usize).collect::<Vec<_>>();
+        assert_eq!(ids[..4], emoji);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), word);
+
+        assert_eq!(
+            tokenizer.decode(&[1 + 0xf0]),
+            Err(crate::DecodeError::InvalidBytes(vec![0xf0]))
+        );
+    }
+
+    #[test]
+    fn char_byte_conversion() {
+        let words = ["café", "cafés", "naïve", "naïveté"].map(|w| w.chars().collect::<Vec<_>>());
+        let chars = crate::train(words.clone(), 10, 2);
+        let bytes = chars.to_bytes();
+        assert_eq!(bytes.vocab_size(), chars.vocab_size());
+
+        for word in &words {
+            let text = word.iter().collect::<String>();
+            let from_chars = chars.decode(&chars.encode(word)).unwrap();
+            let from_bytes = bytes.decode(&bytes.encode(text.as_bytes())).unwrap();
+            assert_eq!(
+                String::from_utf8(from_bytes).unwrap(),
+                from_chars.iter().collect::<String>()
+            );
+        }
+
+        let back = bytes.to_chars().unwrap();
+        for id in 0..chars.vocab_size() {
+            assert_eq!(back.id_to_token(id), chars.id_to_token(id));
+        }
+    }
+
+    #[test]
+    fn gpt2_byte_mapping() {
+        assert_eq!(byte_to_unicode(b'a'), 'a');
+        assert_eq!(byte_to_unicode(b' '), '\u{120}');
+        assert_eq!(byte_to_unicode(b'\n'), '\u{10a}');
+        assert_eq!(byte_to_unicode(0xad), '\u{143}');
+        let chars = (0..=255)
+            .map(byte_to_unicode)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(chars.len(), 256);
+    }
+
+    #[test]
+    fn gpt2_pre_tokenizer() {
+        assert_eq!(
+            split_gpt2("Hello world  42!\n"),
+            ["Hello", " world", " ", " 42", "!", "\n"]
+        );
+        assert_eq!(split_gpt2(" 日本語 🍌"), [" 日本語", " 🍌"]);
+        assert!(split_gpt2("").is_empty());
+
+        assert_eq!(
+            split_at_whitespace(" a  bc\nd "),
+            [" a", "  bc", "\nd", " "]
+        );
+        assert!(split_at_whitespace("").is_empty());
+    }
+
+    #[test]
+    fn byte_level_covers_any_text() {
+        let text = std::fs::read_to_string("data.txt").unwrap();
+        let mut vocab = ByteLevelVocab::new(&text, split_gpt2);
+        vocab.train(100, 2);
+        let tokenizer = vocab.build();
+        assert_eq!(tokenizer.tokenizer().vocab_size(), 1 + 100 + 256);
+
+        let input = "naïve 日本\t🍌  x\n";
+        let ids = tokenizer.encode(input);
+        assert!(!ids.contains(&tokenizer.tokenizer().unk_id()));
+        assert_eq!(tokenizer.decode(&ids).unwrap(), input);
+        assert_eq!(tokenizer.tokenize(" x").concat(), "\u{120}x");
+
+        for b in 0..=255 {
+            assert_eq!(unicode_to_byte(byte_to_unicode(b)), Some(b));
+        }
+        assert_eq!(unicode_to_byte(' '), None);
+    }
+
+    #[test]
+    fn conversions_keep_end_of_word() {
+        let text = "running fast singing ringing sing ring kings thing string";
+        let words = text.split(' ').map(|w| w.chars().collect::<Vec<_>>());
+        let mut vocab = Vocab::with_end_of_word(words.clone(), '$');
+        vocab.train(30, 2);
+        let tokenizer = vocab.build();
+
+        let fallback = vocab.build().with_byte_fallback();
+        assert_eq!(fallback.end_of_word(), Some(&'$'));
+        let sentence = words.collect::<Vec<_>>();
+        let ids = fallback.encode_words(&sentence);
+        assert_eq!(fallback.decode_words(&ids).unwrap(), sentence);
+
+        let bytes = tokenizer.to_bytes();
+        assert_eq!(bytes.end_of_word(), Some(&b'$'));
+        assert_eq!(bytes.to_chars().unwrap().end_of_word(), Some(&'$'));
+    }
+
+    #[test]
+    fn conversions_keep_byte_fallback() {
+        let tokenizer = crate::train(data_words(), 100, 2).with_byte_fallback();
+        let bytes = tokenizer.to_bytes();
+        let chars = bytes.to_chars().unwrap();
+        for text in ["naïve café", "🍌 was the best", "wisdom"] {
+            let word = text.chars().collect::<Vec<_>>();
+            let ids = tokenizer.encode(&word);
+            assert!(!ids.contains(&tokenizer.unk_id()));
+            assert_eq!(bytes.encode(text.as_bytes()), ids);
+            assert_eq!(bytes.decode(&ids).unwrap(), text.as_bytes());
+            assert_eq!(chars.encode(&word), ids);
+        }
+
+        let mut buf = Vec::new();
+        bytes.write_bin(&mut buf).unwrap();
+        let loaded = Tokenizer::<u8>::read_bin(buf.as_slice()).unwrap();
+        assert_eq!(
+            loaded.encode("naïve".as_bytes()),
+            bytes.encode("naïve".as_bytes())
+        );
+    }
+
+    #[test]
+    fn conversions_keep_merge_ranks() {
+        let words = data_words();
+        let ranked = crate::train(words.clone(), 100, 2).with_merge_ranks();
+        let fallback = crate::train(words.clone(), 100, 2)
+            .with_merge_ranks()
+            .with_byte_fallback();
+        let bytes = ranked.to_bytes();
+        let chars = bytes.to_chars().unwrap();
+        for word in &words {
+            assert_eq!(fallback.tokenize(word), ranked.tokenize(word));
+            assert_eq!(chars.tokenize(word), ranked.tokenize(word));
+            let text = word.iter().collect::<String>();
+            assert_eq!(
+                bytes.tokenize(text.as_bytes()).len(),
+                ranked.tokenize(word).len()
+            );
+        }
+    }
+
+    #[test]
+    fn capturing_pre_tokenizer() {
+        // Splits after every `sep`, which a plain function could not know.
+        let sep = '|';
+        let mut vocab = ByteLevelVocab::new("ab|ab|abc|", move |text| {
+            text.split_inclusive(sep).collect()
+        });
+        vocab.train(3, 2);
+        let tokenizer = vocab.build();
+        let input = "ab|c a|";
+        assert_eq!(tokenizer.tokenize(input), ["ab|", "c", "\u{120}", "a", "|"]);
+        assert_eq!(tokenizer.decode(&tokenizer.encode(input)).unwrap(), input);
+
+        let tokenizer = ByteLevelVocab::new("a b", split_at_whitespace).build();
+        assert_eq!(tokenizer.tokenize("b a"), ["b", "\u{120}", "a"]);
+    }
+}
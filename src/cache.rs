@@ -0,0 +1,99 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    hash::Hash,
+};
+
+use crate::Tokenizer;
+
+/// Hit and miss counts of a `CachingTokenizer`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// Memoizes `encode` per word, since the same words recur throughout real
+/// corpora. The cache is unbounded and stores owned ids, so it only grows
+/// with the number of distinct words.
+pub struct CachingTokenizer<C: Ord + Hash + Clone> {
+    tokenizer: Tokenizer<C>,
+    cache: RefCell<HashMap<Vec<C>, Vec<usize>>>,
+    stats: Cell<CacheStats>,
+}
+
+impl<C: Ord + Hash + Clone> CachingTokenizer<C> {
+    pub fn new(tokenizer: Tokenizer<C>) -> Self {
+        Self {
+            tokenizer,
+            cache: RefCell::new(HashMap::new()),
+            stats: Cell::new(CacheStats::default()),
+        }
+    }
+
+    pub fn encode(&self, word: &[C]) -> Vec<usize> {
+        let mut stats = self.stats.get();
+        let cached = self.cache.borrow().get(word).cloned();
+        let ids = match cached {
+            Some(ids) => {
+                stats.hits += 1;
+                ids
+            }
+            None => {
+                stats.misses += 1;
+                let ids = self.tokenizer.encode(word);
+                self.cache.borrow_mut().insert(word.to_vec(), ids.clone());
+                ids
+            }
+        };
+        self.stats.set(stats);
+        ids
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.stats.get()
+    }
+
+    /// Empties the cache and resets the statistics.
+    pub fn clear_cache(&self) {
+        self.cache.borrow_mut().clear();
+        self.stats.set(CacheStats::default());
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer<C> {
+        &self.tokenizer
+    }
+
+    pub fn into_inner(self) -> Tokenizer<C> {
+        self.tokenizer
+    }
+}
+
+impl<C: Ord + Hash + Clone> From<Tokenizer<C>> for CachingTokenizer<C> {
+    fn from(tokenizer: Tokenizer<C>) -> Self {
+        Self::new(tokenizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::data_words;
+
+    #[test]
+    fn cached_encode_agrees() {
+        let words = data_words();
+        let cached = CachingTokenizer::new(crate::train(words.clone(), 100, 2));
+        for word in words.iter().chain(&words) {
+            assert_eq!(cached.encode(word), cached.tokenizer().encode(word));
+        }
+
+        let distinct = words.iter().collect::<std::collections::HashSet<_>>().len();
+        let stats = cached.cache_stats();
+        assert_eq!(stats.misses, distinct);
+        assert_eq!(stats.hits, 2 * words.len() - distinct);
+
+        cached.clear_cache();
+        assert_eq!(cached.cache_stats(), CacheStats::default());
+    }
+}
@@ -0,0 +1,129 @@
+//! Export to the file layout of a HuggingFace `tokenizers` BPE model.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{byte_level::byte_to_unicode, Tokenizer};
+
+impl Tokenizer<u8> {
+    /// Writes `vocab.json` and `merges.txt` into `dir`, rendering bytes with
+    /// the GPT-2 byte-to-unicode mapping.
+    ///
+    /// Every token and special token keeps its id. The model needs all 256
+    /// bytes as base tokens, so bytes that are not tokens get ids after
+    /// `vocab_size`. Merges are listed in id order and cover the learned
+    /// tokens; tokens added without a merge, such as seeded or protected
+    /// ones, only appear in the vocabulary.
+    pub fn export_huggingface(&self, dir: &Path) -> io::Result<()> {
+        let render = |x: &[u8]| x.iter().map(|&b| byte_to_unicode(b)).collect::<String>();
+
+        let mut entries = self
+            .specials
+            .iter()
+            .enumerate()
+            .map(|(id, x)| (x.clone(), id))
+            .collect::<Vec<_>>();
+        for (id, token) in self.tokens.iter().enumerate() {
+            if let Some(token) = token {
+                entries.push((render(token), self.specials.len() + id));
+            }
+        }
+        let mut next = self.vocab_size();
+        for b in 0..=255u8 {
+            if !self.ids.contains_key(&vec![b]) {
+                entries.push((render(&[b]), next));
+                next += 1;
+            }
+        }
+
+        let mut vocab = String::from("{");
+        for (i, (token, id)) in entries.iter().enumerate() {
+            if i > 0 {
+                vocab.push(',');
+            }
+            write!(vocab, "\n  {}: {}", json_string(token), id).unwrap();
+        }
+        vocab.push_str("\n}\n");
+        fs::write(dir.join("vocab.json"), vocab)?;
+
+        let mut merges = io::BufWriter::new(fs::File::create(dir.join("merges.txt"))?);
+        writeln!(merges, "#version: 0.2")?;
+        for token in self.tokens.iter().flatten() {
+            if let Some(&split) = self.splits.get(token) {
+                let (left, right) = token.split_at(split);
+                writeln!(merges, "{} {}", render(left), render(right))?;
+            }
+        }
+        merges.flush()
+    }
+}
+
+fn json_string(x: &str) -> String {
+    let mut result = String::from("\"");
+    for c in x.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(result, "\\u{:04x}", c as u32).unwrap(),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::Vocab;
+
+    use super::*;
+
+    #[test]
+    fn export_layout() {
+        let mut vocab = Vocab::new(["hello world", "hello there"].map(|w| w.bytes()));
+        vocab.train(4, 2);
+        let tokenizer = vocab.build();
+
+        let dir = std::env::temp_dir().join(format!("bpe-hf-export-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        tokenizer.export_huggingface(&dir).unwrap();
+        let vocab = fs::read_to_string(dir.join("vocab.json")).unwrap();
+        let merges = fs::read_to_string(dir.join("merges.txt")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let entries = vocab
+            .trim()
+            .strip_prefix('{')
+            .and_then(|x| x.strip_suffix('}'))
+            .unwrap()
+            .split(",\n")
+            .map(|entry| {
+                let (token, id) = entry.trim().rsplit_once(": ").unwrap();
+                let token = token.strip_prefix('"').unwrap().strip_suffix('"').unwrap();
+                (token.to_string(), id.parse::<usize>().unwrap())
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(entries["<unk>"], 0);
+        assert_eq!(entries.len(), tokenizer.vocab_size() + 256);
+        assert_eq!(entries["Ġ"], tokenizer.vocab_size() + 32);
+        let mut ids = entries.values().copied().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (0..entries.len()).collect::<Vec<_>>());
+
+        let mut lines = merges.lines();
+        assert_eq!(lines.next(), Some("#version: 0.2"));
+        let lines = lines.collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        for line in lines {
+            let (left, right) = line.split_once(' ').unwrap();
+            assert!(entries.contains_key(left) && entries.contains_key(right));
+            assert!(entries.contains_key(&format!("{left}{right}")));
+        }
+    }
+}
@@ -1,178 +1,3531 @@
 use std::{
+    borrow::Cow,
     cell::Cell,
-    collections::{HashMap, HashSet},
-    hash::Hash,
-    ops::AddAssign,
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BTreeSet, BinaryHeap, HashMap, HashSet},
+    fmt,
+    hash::{BuildHasher, Hash},
+    sync::Arc,
 };
 use trie_rs::{Trie, TrieBuilder};
 
-struct VocabChar<C> {
-    char: C,
+use rng::Rng;
+
+pub mod binary;
+pub mod byte_level;
+pub mod cache;
+pub mod huggingface;
+pub mod lines;
+#[cfg(feature = "rayon")]
+mod parallel;
+pub mod registry;
+mod rng;
+pub mod stream;
+pub mod text;
+
+struct VocabChar {
     token_head: Cell<usize>,
+    /// Interned id of the token starting here; only meaningful at heads.
+    piece: Cell<u32>,
+}
+
+/// Interns token contents so the merge loop can hash `u32`s instead of
+/// cloning symbols into every pair key.
+struct Pieces<C> {
+    pieces: Vec<Vec<C>>,
+    ids: HashMap<Vec<C>, u32>,
+}
+
+impl<C: Hash + Eq + Clone> Pieces<C> {
+    fn new() -> Self {
+        Self {
+            pieces: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    fn intern(&mut self, piece: &[C]) -> u32 {
+        if let Some(&id) = self.ids.get(piece) {
+            return id;
+        }
+        let id = self.pieces.len() as u32;
+        self.pieces.push(piece.to_vec());
+        self.ids.insert(piece.to_vec(), id);
+        id
+    }
+
+    fn get(&self, id: u32) -> &[C] {
+        &self.pieces[id as usize]
+    }
+}
+
+struct Merge<C> {
+    token: Vec<C>,
+    split: usize,
+    frequency: usize,
 }
 
 pub struct Vocab<C: Ord + Hash + Clone> {
-    words: Vec<Vec<VocabChar<C>>>,
+    words: Vec<Vec<VocabChar>>,
+    /// How many times each word occurs in the corpus.
+    weights: Vec<usize>,
     tokens: HashSet<Vec<C>>,
+    /// `tokens` in insertion order, which becomes the id order.
+    order: Vec<Vec<C>>,
+    merges: Vec<Merge<C>>,
+    pieces: Pieces<C>,
+    end_of_word: Option<C>,
+    /// Capacity reserved for each word added with `push_word`.
+    word_capacity: usize,
+    /// The pair counts left by the last `merge` or `train`, reused by the
+    /// next one. Anything else changing the words drops it.
+    index: Option<PairIndex<C>>,
+    /// Formats tokens for merge events, see `trace_tokens`.
+    #[cfg(feature = "tracing")]
+    render_token: Option<fn(&[C]) -> String>,
+}
+
+/// One learned merge: `left` and `right` joined into `token`, which occurred
+/// `frequency` times when it was merged.
+#[derive(Debug, Clone)]
+pub struct MergeRecord<'a, C> {
+    token: &'a [C],
+    split: usize,
+    frequency: usize,
+}
+
+/// Token ids are assigned with the special tokens first, followed by the
+/// learned tokens in merge order. Pruning leaves unused ids behind until
+/// `compact_ids` renumbers them.
+///
+/// Tokens are looked up with a `PrefixMatcher`, a `Trie` unless replaced
+/// with `with_matcher`.
+pub struct Tokenizer<C: Ord + Hash + Clone, M = Trie<C>> {
+    matcher: M,
+    specials: Vec<String>,
+    tokens: Vec<Option<Vec<C>>>,
+    ids: HashMap<Vec<C>, usize>,
+    unk_id: usize,
+    normalizer: Option<Normalizer<C>>,
+    protected: Vec<Vec<C>>,
+    protected_trie: Option<Trie<C>>,
+    frequencies: HashMap<Vec<C>, usize>,
+    /// Length of the left part of each learned token's merge.
+    splits: HashMap<Vec<C>, usize>,
+    byte_fallback: Option<ByteFallback<C>>,
+    end_of_word: Option<C>,
+    /// Whether words are segmented by replaying merges, see
+    /// `with_merge_ranks`.
+    merge_ranked: bool,
+}
+
+/// Encodes symbols without a token as byte ids, which are the 256 special
+/// tokens starting at `first_id`.
+struct ByteFallback<C> {
+    first_id: usize,
+    to_bytes: fn(&C) -> Vec<u8>,
+    from_bytes: fn(&[u8]) -> Option<Vec<C>>,
+}
+
+impl<C> Clone for ByteFallback<C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C> Copy for ByteFallback<C> {}
+
+type Normalizer<C> = Arc<dyn Fn(&C) -> C + Send + Sync>;
+
+/// What one merge did: the token it learned, the length of the left piece,
+/// how often the pair occurred, and the number of tokens afterwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeStep<C> {
+    pub token: Vec<C>,
+    pub split: usize,
+    pub frequency: usize,
+    pub vocab_size: usize,
+}
+
+/// One decision of the greedy longest-match loop in `Tokenizer::tokenize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub position: usize,
+    /// Lengths of the vocabulary tokens matching at `position`, ascending.
+    pub candidates: Vec<usize>,
+    pub chosen: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The input has `len` symbols, more than the allowed `max`.
+    InputTooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::InputTooLong { len, max } => {
+                write!(f, "input of {} symbols exceeds the limit of {}", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidId(usize),
+    /// A run of byte fallback ids that does not form valid symbols.
+    InvalidBytes(Vec<u8>),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidId(id) => write!(f, "invalid token id {}", id),
+            DecodeError::InvalidBytes(bytes) => write!(f, "invalid byte sequence {:02x?}", bytes),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError<C> {
+    EmptyToken,
+    /// Symbols of the corpus that are not single-symbol tokens, so encoding
+    /// them falls back to the unknown id. Seed them to cover them.
+    IncompleteCoverage(Vec<C>),
 }
 
-pub struct Tokenizer<C: Ord + Hash + Clone> {
-    trie: Trie<C>,
+impl<C: fmt::Debug> fmt::Display for BuildError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::EmptyToken => write!(f, "empty token"),
+            BuildError::IncompleteCoverage(missing) => {
+                write!(f, "symbols not covered by the vocabulary: {:?}", missing)
+            }
+        }
+    }
+}
+
+impl<C: fmt::Debug> std::error::Error for BuildError<C> {}
+
+/// How `Vocab::merge_scored` rates merge candidates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeScore {
+    /// The number of occurrences, as in `Vocab::merge`.
+    Frequency,
+    /// Occurrences divided by the length of the merged token. This favours
+    /// short tokens that are reused in many words over long ones that only
+    /// occur slightly more often, keeping the vocabulary general at the cost
+    /// of compressing less per token.
+    FrequencyPerLength,
+    /// Pointwise mutual information of the pair,
+    /// `ln(n(ab) * N / (n(a _) * n(_ b)))`, where `n(ab)` is the pair count,
+    /// `n(a _)` and `n(_ b)` count `a` as the left and `b` as the right piece
+    /// of any pair, and `N` is the total number of pairs. A token with
+    /// several splits sums both counts over them. This favours pairs that
+    /// occur together far more than chance, even if they are rare.
+    Pmi,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// No pair occurs at least this many times.
+    NoCandidate(usize),
+    /// The requested token is not formed by any adjacent pair of pieces.
+    NoSuchPair,
 }
 
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::NoCandidate(min_freq) => {
+                write!(f, "no pair occurs at least {} times", min_freq)
+            }
+            MergeError::NoSuchPair => write!(f, "token is not an adjacent pair of pieces"),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
 impl<C: Ord + Hash + Clone> Vocab<C> {
     pub fn new<Words, Word>(words: Words) -> Self
     where
         Words: IntoIterator<Item = Word>,
         Word: IntoIterator<Item = C>,
     {
-        Self {
-            words: words
+        Self::from_weighted(words.into_iter().map(|w| (w, 1)))
+    }
+
+    /// Like `new` with each word given together with its number of
+    /// occurrences, which trains the same as listing every occurrence.
+    /// Words that never occur are dropped.
+    pub fn from_counts<Words, Word>(words: Words) -> Self
+    where
+        Words: IntoIterator<Item = (Word, u64)>,
+        Word: IntoIterator<Item = C>,
+    {
+        Self::from_weighted(
+            words
                 .into_iter()
-                .map(|w| {
-                    w.into_iter()
-                        .map(|char| VocabChar {
-                            char,
-                            token_head: Cell::new(1),
-                        })
-                        .collect::<Vec<_>>()
-                })
-                .filter(|w| !w.is_empty())
-                .collect(),
+                .map(|(w, n)| (w, usize::try_from(n).unwrap_or(usize::MAX))),
+        )
+    }
+
+    /// Like `new` with each word given once together with its number of
+    /// occurrences. Words that never occur are dropped.
+    fn from_weighted<Words, Word>(words: Words) -> Self
+    where
+        Words: IntoIterator<Item = (Word, usize)>,
+        Word: IntoIterator<Item = C>,
+    {
+        let mut pieces = Pieces::new();
+        let (words, weights) = words
+            .into_iter()
+            .map(|(w, weight)| {
+                let word = w
+                    .into_iter()
+                    .map(|char| VocabChar {
+                        token_head: Cell::new(1),
+                        piece: Cell::new(pieces.intern(&[char])),
+                    })
+                    .collect::<Vec<_>>();
+                (word, weight)
+            })
+            .filter(|(w, weight)| !w.is_empty() && *weight > 0)
+            .unzip();
+        Self {
+            words,
+            weights,
             tokens: HashSet::new(),
+            order: Vec::new(),
+            merges: Vec::new(),
+            pieces,
+            end_of_word: None,
+            word_capacity: 0,
+            index: None,
+            #[cfg(feature = "tracing")]
+            render_token: None,
+        }
+    }
+
+    /// An empty vocabulary with room for `num_words` words, to be added with
+    /// `push_word`, of about `avg_word_len` symbols each. For large corpora,
+    /// a word count from a first pass and a rough average length avoid
+    /// repeatedly growing the corpus while loading it; overestimates only
+    /// cost memory. Training is unaffected.
+    pub fn with_capacity(num_words: usize, avg_word_len: usize) -> Self {
+        let mut vocab = Self::new(std::iter::empty::<Vec<C>>());
+        vocab.words.reserve(num_words);
+        vocab.weights.reserve(num_words);
+        vocab.word_capacity = avg_word_len;
+        vocab
+    }
+
+    /// Adds a word to the corpus, ignoring empty ones. Meant for building
+    /// the corpus before training; merges learned so far are not applied.
+    pub fn push_word<Word: IntoIterator<Item = C>>(&mut self, word: Word) {
+        let mut chars = Vec::with_capacity(self.word_capacity);
+        for char in word {
+            chars.push(VocabChar {
+                token_head: Cell::new(1),
+                piece: Cell::new(self.pieces.intern(&[char])),
+            });
+        }
+        if !chars.is_empty() {
+            self.words.push(chars);
+            self.weights.push(1);
+            self.index = None;
+        }
+    }
+
+    /// Builds one corpus from several, each occurrence of a word in a corpus
+    /// counting `weight` times, so a small domain corpus can be given
+    /// weight comparable to a large general one. If the smallest weighted
+    /// count of a distinct word is below 1, every count is scaled up so it
+    /// becomes 1 before rounding to whole occurrences, so no word is lost;
+    /// `min_freq` applies to the counts after scaling. Words with no
+    /// positive count are dropped.
+    pub fn from_balanced_corpora<Words, Word>(corpora: Vec<(Words, f64)>) -> Self
+    where
+        Words: IntoIterator<Item = Word>,
+        Word: IntoIterator<Item = C>,
+    {
+        let mut counts = Vec::<(Vec<C>, f64)>::new();
+        let mut index = HashMap::new();
+        for (words, weight) in corpora {
+            for word in words {
+                let word = word.into_iter().collect::<Vec<_>>();
+                let i = *index.entry(word.clone()).or_insert_with(|| {
+                    counts.push((word, 0.0));
+                    counts.len() - 1
+                });
+                counts[i].1 += weight;
+            }
+        }
+        let smallest = counts
+            .iter()
+            .map(|(_, n)| *n)
+            .filter(|n| *n > 0.0)
+            .fold(f64::INFINITY, f64::min);
+        let scale = if smallest < 1.0 { 1.0 / smallest } else { 1.0 };
+        Self::from_weighted(
+            counts
+                .into_iter()
+                .filter(|(_, n)| *n > 0.0)
+                .map(|(w, n)| (w, (n * scale).round() as usize)),
+        )
+    }
+
+    /// Appends `marker` to every word, so word-final pieces are learned as
+    /// tokens distinct from the same symbols inside a word.
+    pub fn with_end_of_word<Words, Word>(words: Words, marker: C) -> Self
+    where
+        Words: IntoIterator<Item = Word>,
+        Word: IntoIterator<Item = C>,
+    {
+        let mut vocab = Self::new(
+            words
+                .into_iter()
+                .map(|w| w.into_iter().chain([marker.clone()])),
+        );
+        vocab.end_of_word = Some(marker);
+        vocab
+    }
+
+    /// Builds a vocabulary from words given as sequences of base units,
+    /// which merges never split. Units of more than one symbol become tokens
+    /// so the built tokenizer keeps them whole too.
+    pub fn from_units<Words, Word>(words: Words) -> Self
+    where
+        Words: IntoIterator<Item = Word>,
+        Word: IntoIterator<Item = Vec<C>>,
+    {
+        let words = words
+            .into_iter()
+            .map(|w| w.into_iter().filter(|x| !x.is_empty()).collect::<Vec<_>>())
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>();
+        let mut vocab = Self::new(words.iter().map(|w| w.concat()));
+        for (chars, word) in vocab.words.iter().zip(&words) {
+            let mut pos = 0;
+            for unit in word {
+                chars[pos].token_head.set(unit.len());
+                chars[pos].piece.set(vocab.pieces.intern(unit));
+                pos += unit.len();
+            }
+        }
+        for unit in words.into_iter().flatten() {
+            if unit.len() > 1 {
+                vocab.insert_token(unit);
+            }
+        }
+        vocab
+    }
+
+    /// Like `new`, but every occurrence of an `atomic` sequence, matched
+    /// leftmost first and longest on overlap, is one base unit as in
+    /// `from_units`. Atomic units can merge with their neighbours but are
+    /// never split by a merge.
+    pub fn with_atomic_units<Words, Word>(words: Words, atomic: Vec<Vec<C>>) -> Self
+    where
+        Words: IntoIterator<Item = Word>,
+        Word: IntoIterator<Item = C>,
+    {
+        let atomic = atomic
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        let trie = build_trie(&atomic);
+        Self::from_units(words.into_iter().map(|w| {
+            let word = w.into_iter().collect::<Vec<_>>();
+            let mut units = Vec::new();
+            let mut pos = 0;
+            while pos < word.len() {
+                let n = trie
+                    .common_prefix_lengths(&word[pos..])
+                    .into_iter()
+                    .max()
+                    .unwrap_or(1);
+                units.push(word[pos..pos + n].to_vec());
+                pos += n;
+            }
+            units
+        }))
+    }
+
+    /// Starts from the segmentation `tokenizer` gives each word, so further
+    /// merges build on its whole vocabulary. Its tokens come first in the id
+    /// order of the built tokenizer, and its learned merges come first in
+    /// the merge records.
+    pub fn from_tokenizer<Words, Word>(tokenizer: &Tokenizer<C>, words: Words) -> Self
+    where
+        Words: IntoIterator<Item = Word>,
+        Word: IntoIterator<Item = C>,
+    {
+        let words = words
+            .into_iter()
+            .map(|w| w.into_iter().collect::<Vec<_>>())
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>();
+        let mut vocab = Self::new(words.iter().cloned());
+        for token in tokenizer.tokens.iter().flatten() {
+            vocab.insert_token(token.clone());
+            if let Some(&split) = tokenizer.splits.get(token) {
+                vocab.merges.push(Merge {
+                    token: token.clone(),
+                    split,
+                    frequency: tokenizer.frequencies.get(token).copied().unwrap_or(0),
+                });
+            }
+        }
+
+        for (chars, word) in vocab.words.iter().zip(&words) {
+            let mut pos = 0;
+            for piece in tokenizer.tokenize(word) {
+                chars[pos].token_head.set(piece.len());
+                chars[pos].piece.set(vocab.pieces.intern(piece));
+                pos += piece.len();
+            }
+        }
+        vocab
+    }
+
+    /// Undoes all training so the corpus can be trained again, splitting
+    /// every word back into single symbols and forgetting every token,
+    /// including seeded ones and the units of `from_units`.
+    pub fn reset(&mut self) {
+        let mut pieces = Pieces::new();
+        for x in self.words.iter().flatten() {
+            let symbol = self.pieces.get(x.piece.get())[0].clone();
+            x.token_head.set(1);
+            x.piece.set(pieces.intern(&[symbol]));
         }
+        self.pieces = pieces;
+        self.tokens.clear();
+        self.order.clear();
+        self.merges.clear();
+        self.index = None;
+    }
+
+    fn insert_token(&mut self, token: Vec<C>) {
+        if self.tokens.insert(token.clone()) {
+            self.order.push(token);
+        }
+    }
+
+    /// Merges the most frequent pair, as `merge_filtered` with every pair
+    /// allowed. The pair counts and the ranking of candidate tokens are kept
+    /// for the next `merge` or `train`, so a run of merges only recounts the
+    /// words each one changes.
+    pub fn merge(&mut self, min_freq: usize) -> Result<MergeStep<C>, MergeError> {
+        let mut index = self.take_index();
+        let step = self.merge_indexed(&mut index, min_freq);
+        self.index = Some(index);
+        step.ok_or(MergeError::NoCandidate(min_freq))
+    }
+
+    /// Merges lazily, one `merge(min_freq)` per item, until no pair is
+    /// frequent enough. Each merge is applied before its step is returned,
+    /// so a step rejected by `take_while` is already learned.
+    pub fn merge_iter(&mut self, min_freq: usize) -> impl Iterator<Item = MergeStep<C>> + '_ {
+        std::iter::from_fn(move || self.merge(min_freq).ok())
+    }
+
+    /// Every token the next merge could create with how often it occurs,
+    /// most frequent first, in the order `merge` would prefer them.
+    pub fn pair_frequencies(&self, min_freq: usize) -> Vec<(Vec<C>, usize)> {
+        let pairs = count_pairs::<RandomState>(self.weighted_words(), |_| true);
+        rank_candidates::<C, RandomState>(&self.pieces, weighted_pairs(&pairs), min_freq, |_, _| {
+            true
+        })
+        .into_iter()
+        .map(|(token, _, frequency)| (token, frequency))
+        .collect()
+    }
+
+    /// The token and frequency the next `merge(min_freq)` would record,
+    /// computed without changing anything.
+    pub fn peek_merge(&self, min_freq: usize) -> Result<(Vec<C>, usize), MergeError> {
+        self.pair_frequencies(min_freq)
+            .into_iter()
+            .next()
+            .ok_or(MergeError::NoCandidate(min_freq))
     }
 
-    pub fn merge(&mut self, min_freq: usize) -> Result<(), ()> {
-        let mut pairs = HashMap::<Vec<C>, Vec<&VocabChar<C>>>::new();
-        for word in &self.words {
+    /// Merges whichever adjacent pairs of pieces form `token`, regardless of
+    /// how frequent they are, so merges can be chosen by hand.
+    pub fn apply_merge(&mut self, token: Vec<C>) -> Result<MergeStep<C>, MergeError> {
+        let allow = |a: &[C], b: &[C]| {
+            a.len() + b.len() == token.len() && token.starts_with(a) && token.ends_with(b)
+        };
+        self.merge_in::<RandomState>(None, 1, allow, |_, _| 0)
+            .map_err(|_| MergeError::NoSuchPair)
+    }
+
+    /// Whether `merge(min_freq)` would succeed. Scans the corpus without
+    /// recording occurrences, stopping as soon as a pair is frequent enough.
+    pub fn can_merge(&self, min_freq: usize) -> bool {
+        let mut pairs = HashMap::<(u32, u32), usize>::new();
+        for (word, weight) in self.weighted_words() {
             let mut a_pos = 0;
             loop {
-                let a_len = word[a_pos].token_head.get();
-                let b_pos = a_pos + a_len;
+                let b_pos = a_pos + word[a_pos].token_head.get();
                 if b_pos >= word.len() {
                     break;
                 }
-                let b_len = word[b_pos].token_head.get();
+                let count = pairs
+                    .entry((word[a_pos].piece.get(), word[b_pos].piece.get()))
+                    .or_default();
+                *count += weight;
+                if *count >= min_freq {
+                    return true;
+                }
+                a_pos = b_pos;
+            }
+        }
+
+        // Different splits of the same token count together.
+        let mut candidates = HashMap::<Vec<C>, usize>::new();
+        pairs.into_iter().any(|(key, count)| {
+            let token = [self.pieces.get(key.0), self.pieces.get(key.1)].concat();
+            let total = candidates.entry(token).or_default();
+            *total += count;
+            *total >= min_freq
+        })
+    }
 
-                let token = word[a_pos..][..a_len + b_len]
+    /// Like `merge`, but only pairs for which `allow(left, right)` returns
+    /// true are candidates.
+    pub fn merge_filtered(
+        &mut self,
+        min_freq: usize,
+        allow: impl Fn(&[C], &[C]) -> bool,
+    ) -> Result<MergeStep<C>, MergeError> {
+        self.merge_hashed::<RandomState>(min_freq, allow)
+    }
+
+    /// The body of `merge_filtered`, generic over the hasher of its maps so
+    /// tests can check that the result does not depend on iteration order.
+    fn merge_hashed<S: BuildHasher + Default>(
+        &mut self,
+        min_freq: usize,
+        allow: impl Fn(&[C], &[C]) -> bool,
+    ) -> Result<MergeStep<C>, MergeError> {
+        self.merge_in::<S>(None, min_freq, allow, |_, _| 0)
+    }
+
+    /// Picks the merge from the pairs in the words at `sample`, or in every
+    /// word if it is `None`, and applies it to every word. `choose` returns
+    /// the index of the merge among the ranked candidates.
+    fn merge_in<S: BuildHasher + Default>(
+        &mut self,
+        sample: Option<&[usize]>,
+        min_freq: usize,
+        allow: impl Fn(&[C], &[C]) -> bool,
+        choose: impl FnOnce(&[Candidate<C>], &Pairs<'_, S>) -> usize,
+    ) -> Result<MergeStep<C>, MergeError> {
+        let started = MergeTimer::start();
+        let pairs = match sample {
+            Some(sample) => count_pairs::<S>(
+                sample
                     .iter()
-                    .map(|x| x.char.clone())
-                    .collect();
+                    .map(|&i| (&self.words[i][..], self.weights[i])),
+                |_| true,
+            ),
+            None => count_pairs::<S>(
+                self.words
+                    .iter()
+                    .map(|w| &w[..])
+                    .zip(self.weights.iter().copied()),
+                |_| true,
+            ),
+        };
 
-                pairs.entry(token).or_default().push(&word[a_pos]);
-                a_pos = b_pos;
+        let mut candidates =
+            rank_candidates::<C, S>(&self.pieces, weighted_pairs(&pairs), min_freq, allow);
+        if candidates.is_empty() {
+            return Err(MergeError::NoCandidate(min_freq));
+        }
+        let index = choose(&candidates, &pairs);
+        let (token, keys, mut frequency) = candidates.swap_remove(index);
+        self.index = None;
+
+        // The same token can come from different splits; record the first.
+        let first = keys.iter().min_by_key(|k| pairs[k].0).unwrap();
+        let split = self.pieces.get(first.0).len();
+        let pairs = match sample {
+            Some(_) => {
+                let pairs = count_pairs::<S>(
+                    self.words
+                        .iter()
+                        .map(|w| &w[..])
+                        .zip(self.weights.iter().copied()),
+                    |k| keys.contains(&k),
+                );
+                frequency = pairs.values().map(|x| x.1).sum();
+                pairs
+            }
+            None => pairs,
+        };
+        let piece = self.pieces.intern(&token);
+        for key in &keys {
+            for a in pairs.get(key).into_iter().flat_map(|x| &x.2) {
+                a.token_head.set(token.len());
+                a.piece.set(piece);
             }
         }
+        Ok(self.record_merge(token, split, frequency, started))
+    }
 
-        let best = pairs
-            .into_iter()
-            .filter(|(_, v)| v.len() >= min_freq)
-            .max_by_key(|(_, v)| v.len());
-        let best = best.ok_or(())?;
+    fn record_merge(
+        &mut self,
+        token: Vec<C>,
+        split: usize,
+        frequency: usize,
+        started: MergeTimer,
+    ) -> MergeStep<C> {
+        self.merges.push(Merge {
+            token: token.clone(),
+            split,
+            frequency,
+        });
+        self.insert_token(token.clone());
+        let step = MergeStep {
+            token,
+            split,
+            frequency,
+            vocab_size: self.tokens.len(),
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            token = self.render_token.map(|render| render(&step.token)),
+            token_len = step.token.len(),
+            split,
+            frequency,
+            vocab_size = step.vocab_size,
+            elapsed_us = started.started.elapsed().as_micros() as u64,
+            "merge"
+        );
+        #[cfg(not(feature = "tracing"))]
+        let _ = started;
+        step
+    }
+
+    /// Like `merge`, but picks the candidate with the highest pointwise
+    /// mutual information, see `MergeScore::Pmi`.
+    pub fn merge_pmi(&mut self, min_freq: usize) -> Result<MergeStep<C>, MergeError> {
+        self.merge_scored(min_freq, MergeScore::Pmi)
+    }
+
+    /// Like `merge`, but picks the candidate that `score` rates highest among
+    /// those occurring at least `min_freq` times. Ties go to the candidate
+    /// `merge` would prefer.
+    pub fn merge_scored(
+        &mut self,
+        min_freq: usize,
+        score: MergeScore,
+    ) -> Result<MergeStep<C>, MergeError> {
+        self.merge_in::<RandomState>(
+            None,
+            min_freq,
+            |_, _| true,
+            |candidates, pairs| {
+                let scores = match score {
+                    MergeScore::Frequency => return 0,
+                    MergeScore::FrequencyPerLength => candidates
+                        .iter()
+                        .map(|(token, _, frequency)| *frequency as f64 / token.len() as f64)
+                        .collect::<Vec<_>>(),
+                    MergeScore::Pmi => {
+                        let mut left = HashMap::<u32, usize>::new();
+                        let mut right = HashMap::<u32, usize>::new();
+                        let mut total = 0;
+                        for (&(a, b), &(_, count, _)) in pairs {
+                            *left.entry(a).or_default() += count;
+                            *right.entry(b).or_default() += count;
+                            total += count;
+                        }
+                        candidates
+                            .iter()
+                            .map(|(_, keys, frequency)| {
+                                let expected = keys
+                                    .iter()
+                                    .map(|(a, b)| left[a] as f64 * right[b] as f64)
+                                    .sum::<f64>();
+                                (*frequency as f64 * total as f64 / expected).ln()
+                            })
+                            .collect()
+                    }
+                };
+                let mut best = 0;
+                for (i, &x) in scores.iter().enumerate() {
+                    if x > scores[best] {
+                        best = i;
+                    }
+                }
+                best
+            },
+        )
+    }
+
+    /// Merges until `vocab_size` tokens are learned or no pair reaches
+    /// `min_freq`, the same as calling `merge` in a loop.
+    pub fn train(&mut self, vocab_size: usize, min_freq: usize) {
+        self.train_with_progress(vocab_size, min_freq, |_| {})
+    }
 
-        for a in best.1 {
-            a.token_head.set(best.0.len());
+    /// Like `train`, calling `progress` after every merge.
+    pub fn train_with_progress(
+        &mut self,
+        vocab_size: usize,
+        min_freq: usize,
+        mut progress: impl FnMut(&MergeStep<C>),
+    ) {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("train", vocab_size, min_freq).entered();
+        let mut index = self.take_index();
+        while self.tokens.len() < vocab_size {
+            let Some(step) = self.merge_indexed(&mut index, min_freq) else {
+                break;
+            };
+            progress(&step);
         }
-        self.tokens.insert(best.0);
+        self.index = Some(index);
+        #[cfg(feature = "tracing")]
+        tracing::info!(vocab_size = self.tokens.len(), "training finished");
+    }
 
-        Ok(())
+    /// The pair counts of the words as they are now, reusing the ones kept by
+    /// the last `merge` or `train` if nothing changed the words since.
+    fn take_index(&mut self) -> PairIndex<C> {
+        self.index
+            .take()
+            .unwrap_or_else(|| PairIndex::new(&self.pieces, self.weighted_words()))
+    }
+
+    /// The merge `merge` makes, keeping `index` up to date with it.
+    fn merge_indexed<S: BuildHasher + Default>(
+        &mut self,
+        index: &mut PairIndex<C, S>,
+        min_freq: usize,
+    ) -> Option<MergeStep<C>> {
+        let started = MergeTimer::start();
+        let id = index.best(min_freq)?;
+        let (token, keys, frequency) = index.candidates[id].clone();
+
+        // As in `merge_in`, the split recorded is that of the pair seen
+        // first in corpus order.
+        let first_seen = |key: &(u32, u32)| {
+            let i = *index.words[key].first().unwrap();
+            let pos = word_pairs(&self.words[i])
+                .find(|(k, _)| k == key)
+                .unwrap()
+                .1;
+            (i, pos)
+        };
+        let first = keys.iter().min_by_key(|k| first_seen(k)).unwrap();
+        let split = self.pieces.get(first.0).len();
+
+        let piece = self.pieces.intern(&token);
+        let affected = keys
+            .iter()
+            .flat_map(|k| &index.words[k])
+            .copied()
+            .collect::<BTreeSet<_>>();
+        for i in affected {
+            let (word, weight) = (&self.words[i], self.weights[i]);
+            index.remove_word(i, word, weight);
+            let heads = word_pairs(word)
+                .filter(|(k, _)| keys.contains(k))
+                .map(|(_, a_pos)| a_pos)
+                .collect::<Vec<_>>();
+            for a_pos in heads {
+                word[a_pos].token_head.set(token.len());
+                word[a_pos].piece.set(piece);
+            }
+            index.add_word(&self.pieces, i, word, weight);
+        }
+        index.flush();
+        Some(self.record_merge(token, split, frequency, started))
+    }
+
+    /// Approximate `train` for large corpora: each merge is chosen from the
+    /// pair counts of `sample` randomly picked words, then applied to the
+    /// whole corpus. A pair that is frequent overall can be missed if it is
+    /// rare in a sample, and `min_freq` is checked against the sample
+    /// counts, but recorded frequencies are exact. With `sample` at least
+    /// the number of words this is exactly `train`.
+    pub fn train_sampled(&mut self, vocab_size: usize, min_freq: usize, sample: usize, seed: u64) {
+        if sample >= self.words.len() {
+            return self.train(vocab_size, min_freq);
+        }
+        let mut rng = Rng::new(seed);
+        let mut indices = (0..self.words.len()).collect::<Vec<_>>();
+        while self.tokens.len() < vocab_size {
+            // Partial Fisher-Yates shuffle, sorted so the first-seen order
+            // of pairs follows the corpus.
+            for i in 0..sample {
+                let j = i + rng.below(indices.len() - i);
+                indices.swap(i, j);
+            }
+            let mut picked = indices[..sample].to_vec();
+            picked.sort_unstable();
+            if self
+                .merge_in::<RandomState>(Some(&picked), min_freq, |_, _| true, |_, _| 0)
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Merges until the number of tokens `val` is split into has not
+    /// decreased for `patience` consecutive merges, or no pair reaches
+    /// `min_freq`. The merges that did not help are kept.
+    pub fn train_early_stop(&mut self, val: &[Vec<C>], patience: usize, min_freq: usize) {
+        let count = |vocab: &Self| {
+            let tokenizer = Tokenizer::from_ordered(vocab.order.clone());
+            val.iter().map(|w| tokenizer.count_tokens(w)).sum::<usize>()
+        };
+        let mut best = count(self);
+        let mut stale = 0;
+        while stale < patience && self.merge(min_freq).is_ok() {
+            let current = count(self);
+            if current < best {
+                best = current;
+                stale = 0;
+            } else {
+                stale += 1;
+            }
+        }
+    }
+
+    /// Merges until no pair reaches the minimum frequency that `schedule`
+    /// returns for the current number of learned tokens.
+    pub fn train_with_schedule(&mut self, schedule: impl Fn(usize) -> usize) {
+        while self.merge(schedule(self.tokens.len())).is_ok() {}
+    }
+
+    /// Merges the most frequent pair until `max_vocab` tokens are learned,
+    /// stopping early only once no pair occurs more than once.
+    pub fn train_capped(&mut self, max_vocab: usize) {
+        self.train(max_vocab, 2)
     }
 
     pub fn build(&self) -> Tokenizer<C>
     where
         C: Ord,
     {
-        let mut builder = TrieBuilder::new();
-        for x in &self.tokens {
-            builder.push(x)
+        self.finish(Tokenizer::from_ordered(self.order.clone()))
+    }
+
+    /// Copies the training metadata onto a freshly built tokenizer.
+    fn finish(&self, mut tokenizer: Tokenizer<C>) -> Tokenizer<C> {
+        tokenizer.frequencies = self
+            .merges
+            .iter()
+            .map(|m| (m.token.clone(), m.frequency))
+            .collect();
+        tokenizer.splits = self
+            .merges
+            .iter()
+            .map(|m| (m.token.clone(), m.split))
+            .collect();
+        tokenizer.end_of_word = self.end_of_word.clone();
+        tokenizer
+    }
+
+    /// Like `build`, but fails unless every symbol of the corpus is a token
+    /// on its own.
+    pub fn build_checked(&self) -> Result<Tokenizer<C>, BuildError<C>> {
+        let mut missing = self
+            .symbols()
+            .filter(|c| !self.tokens.contains(std::slice::from_ref(*c)))
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(BuildError::IncompleteCoverage(missing));
         }
-        Tokenizer {
-            trie: builder.build(),
+        Tokenizer::from_tokens(self.order.iter().cloned()).map(|x| self.finish(x))
+    }
+
+    /// Adds tokens to the vocabulary without changing how the corpus is
+    /// segmented, typically single symbols for full coverage.
+    pub fn with_seed_tokens<Tokens>(mut self, tokens: Tokens) -> Self
+    where
+        Tokens: IntoIterator<Item = Vec<C>>,
+    {
+        for token in tokens {
+            if !token.is_empty() {
+                self.insert_token(token);
+            }
         }
+        self
+    }
+
+    /// The distinct symbols of the corpus, which all need a single-symbol
+    /// token for every word to encode without `unk_id`. Passing them to
+    /// `with_seed_tokens` guarantees that.
+    pub fn required_base_alphabet(&self) -> HashSet<C> {
+        self.symbols().cloned().collect()
+    }
+
+    fn weighted_words(&self) -> impl Iterator<Item = (&[VocabChar], usize)> {
+        self.words
+            .iter()
+            .map(|w| &w[..])
+            .zip(self.weights.iter().copied())
+    }
+
+    /// Every symbol position of the corpus.
+    fn symbols(&self) -> impl Iterator<Item = &C> {
+        // The piece at any position starts with the symbol there.
+        self.words
+            .iter()
+            .flatten()
+            .map(|x| &self.pieces.get(x.piece.get())[0])
     }
 
     pub fn tokens(&self) -> &HashSet<Vec<C>> {
         &self.tokens
     }
+
+    /// Learned merges in the order they were made.
+    pub fn merge_records(&self) -> impl Iterator<Item = MergeRecord<'_, C>> {
+        self.merges.iter().map(|m| MergeRecord {
+            token: &m.token,
+            split: m.split,
+            frequency: m.frequency,
+        })
+    }
 }
 
-impl<C: Ord + Hash + Clone> Tokenizer<C> {
-    pub fn tokenize<'a>(&self, mut word: &'a [C]) -> Vec<&'a [C]> {
-        let mut result = Vec::new();
-        while !word.is_empty() {
-            let n = self
-                .trie
-                .common_prefix_search(word)
-                .into_iter()
-                .map(|x| x.len())
-                .max()
-                .unwrap_or(1);
-            result.push(&word[..n]);
-            word = &word[n..];
-        }
-        result
+#[cfg(feature = "tracing")]
+impl<C: Ord + Hash + Clone + fmt::Debug> Vocab<C> {
+    /// Includes each merged token, formatted with `Debug`, in the `tracing`
+    /// merge events, which otherwise only give its length.
+    pub fn trace_tokens(mut self) -> Self {
+        self.render_token = Some(|token| format!("{token:?}"));
+        self
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        fs::File,
-        io::{BufRead, BufReader},
-    };
+impl<'a, C> MergeRecord<'a, C> {
+    pub fn left(&self) -> &'a [C] {
+        &self.token[..self.split]
+    }
 
-    use super::*;
+    pub fn right(&self) -> &'a [C] {
+        &self.token[self.split..]
+    }
 
-    #[test]
-    fn case01() {
-        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
-        let mut vocab = Vocab::new([data.clone()]);
-        for _ in 0..4 {
-            vocab.merge();
-        }
+    pub fn token(&self) -> &'a [C] {
+        self.token
+    }
 
-        let tokenizer = vocab.build();
+    pub fn frequency(&self) -> usize {
+        self.frequency
+    }
+}
 
-        let tokenized = tokenizer
-            .tokenize(&data)
+/// Finds the vocabulary tokens that start a sequence of symbols.
+pub trait PrefixMatcher<C> {
+    /// Lengths of all tokens that are a prefix of `input`, in any order.
+    fn common_prefix_lengths(&self, input: &[C]) -> Vec<usize>;
+}
+
+impl<C: Ord + Clone> PrefixMatcher<C> for Trie<C> {
+    fn common_prefix_lengths(&self, input: &[C]) -> Vec<usize> {
+        self.common_prefix_search(input)
             .into_iter()
-            .map(|cs| cs.iter().copied().collect::<String>())
-            .collect::<Vec<_>>()
-            .join(" ");
-        dbg!(tokenized);
+            .map(|x| x.len())
+            .collect()
     }
+}
 
-    #[test]
-    fn case02() {
-        let mut vocab = Vocab::new(
-            BufReader::new(File::open("data.txt").unwrap())
-                .lines()
-                .filter_map(|x| x.map(|x| x.chars().collect::<Vec<_>>()).ok()),
-        );
+/// The order in which each adjacent pair of pieces was first seen, its
+/// weighted count and its occurrences.
+type Pairs<'a, S> = HashMap<(u32, u32), (usize, usize, Vec<&'a VocabChar>), S>;
 
-        for _ in 0..100 {
-            vocab.merge()
+/// Counts the pairs for which `keep` returns true in weighted words.
+fn count_pairs<'a, S: BuildHasher + Default>(
+    words: impl Iterator<Item = (&'a [VocabChar], usize)>,
+    keep: impl Fn((u32, u32)) -> bool,
+) -> Pairs<'a, S> {
+    let mut pairs = HashMap::<_, (usize, usize, Vec<_>), S>::default();
+    for (word, weight) in words {
+        for (key, a_pos) in word_pairs(word) {
+            if keep(key) {
+                let seen = pairs.len();
+                let entry = pairs.entry(key).or_insert_with(|| (seen, 0, Vec::new()));
+                entry.1 += weight;
+                entry.2.push(&word[a_pos]);
+            }
         }
+    }
+    pairs
+}
 
-        let tokenizer = vocab.build();
+fn weighted_pairs<'a, S>(
+    pairs: &'a Pairs<'_, S>,
+) -> impl Iterator<Item = ((u32, u32), usize)> + 'a {
+    pairs.iter().map(|(&key, &(_, count, _))| (key, count))
+}
 
-        let test_words = BufReader::new(File::open("data.txt").unwrap())
-            .lines()
-            .take(10)
-            .map(|x| x.map(|x| x.chars().collect::<Vec<_>>()))
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
+/// When a merge started, for the elapsed time in its `tracing` event.
+/// Without the feature it holds nothing.
+#[derive(Clone, Copy)]
+struct MergeTimer {
+    #[cfg(feature = "tracing")]
+    started: std::time::Instant,
+}
+
+impl MergeTimer {
+    fn start() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            started: std::time::Instant::now(),
+        }
+    }
+}
+
+/// The pairs of the pieces in each word and their weighted counts, updated
+/// word by word as merges change them, together with the tokens they form
+/// and a heap of those tokens by total count.
+/// Generic over the hasher of its maps, like `merge_hashed`, so tests can
+/// check that training does not depend on iteration order.
+struct PairIndex<C, S = RandomState> {
+    counts: HashMap<(u32, u32), usize, S>,
+    /// The words each pair occurs in.
+    words: HashMap<(u32, u32), BTreeSet<usize>, S>,
+    /// The index in `candidates` of the token each pair forms.
+    pair_candidates: HashMap<(u32, u32), usize, S>,
+    candidate_ids: HashMap<Vec<C>, usize, S>,
+    /// Every token some pair forms or formed, with the pairs forming it now.
+    candidates: Vec<Candidate<C>>,
+    /// Candidates by total count, then smallest token first. An entry is
+    /// stale once its count differs from the candidate's current one; every
+    /// change pushes a new entry.
+    heap: BinaryHeap<(usize, Reverse<Vec<C>>)>,
+    /// Candidates whose total changed since the last `flush`.
+    touched: Vec<usize>,
+}
+
+impl<C: Ord + Hash + Clone, S: BuildHasher + Default> PairIndex<C, S> {
+    fn new<'a>(pieces: &Pieces<C>, words: impl Iterator<Item = (&'a [VocabChar], usize)>) -> Self {
+        let mut index = Self {
+            counts: HashMap::default(),
+            words: HashMap::default(),
+            pair_candidates: HashMap::default(),
+            candidate_ids: HashMap::default(),
+            candidates: Vec::new(),
+            heap: BinaryHeap::new(),
+            touched: Vec::new(),
+        };
+        for (i, (word, weight)) in words.enumerate() {
+            index.add_word(pieces, i, word, weight);
+        }
+        index.flush();
+        index
+    }
+
+    fn add_word(&mut self, pieces: &Pieces<C>, i: usize, word: &[VocabChar], weight: usize) {
+        for (key, _) in word_pairs(word) {
+            let count = self.counts.entry(key).or_default();
+            *count += weight;
+            self.words.entry(key).or_default().insert(i);
+
+            let id = match self.pair_candidates.get(&key) {
+                Some(&id) => id,
+                None => {
+                    let token = [pieces.get(key.0), pieces.get(key.1)].concat();
+                    let id = *self.candidate_ids.entry(token.clone()).or_insert_with(|| {
+                        self.candidates.push((token, Vec::new(), 0));
+                        self.candidates.len() - 1
+                    });
+                    self.candidates[id].1.push(key);
+                    self.pair_candidates.insert(key, id);
+                    id
+                }
+            };
+            self.candidates[id].2 += weight;
+            self.touched.push(id);
+        }
+    }
+
+    fn remove_word(&mut self, i: usize, word: &[VocabChar], weight: usize) {
+        for (key, _) in word_pairs(word) {
+            let id = self.pair_candidates[&key];
+            self.candidates[id].2 -= weight;
+            self.touched.push(id);
+
+            let count = self.counts.get_mut(&key).unwrap();
+            *count -= weight;
+            if *count == 0 {
+                self.counts.remove(&key);
+                self.pair_candidates.remove(&key);
+                self.candidates[id].1.retain(|&k| k != key);
+            }
+            if let Some(words) = self.words.get_mut(&key) {
+                words.remove(&i);
+                if words.is_empty() {
+                    self.words.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Pushes the new totals of the touched candidates onto the heap.
+    fn flush(&mut self) {
+        self.touched.sort_unstable();
+        self.touched.dedup();
+        for id in self.touched.drain(..) {
+            let (token, _, total) = &self.candidates[id];
+            if *total > 0 {
+                self.heap.push((*total, Reverse(token.clone())));
+            }
+        }
+    }
+
+    /// The candidate `rank_candidates` would put first, dropping stale heap
+    /// entries on the way.
+    fn best(&mut self, min_freq: usize) -> Option<usize> {
+        loop {
+            let (total, Reverse(token)) = self.heap.peek()?;
+            let id = self.candidate_ids[token];
+            if self.candidates[id].2 != *total {
+                self.heap.pop();
+                continue;
+            }
+            return (*total >= min_freq).then_some(id);
+        }
+    }
+}
+
+/// The adjacent pairs of pieces in `word` with the position of the left one.
+fn word_pairs(word: &[VocabChar]) -> impl Iterator<Item = ((u32, u32), usize)> + '_ {
+    let mut a_pos = 0;
+    std::iter::from_fn(move || {
+        let b_pos = a_pos + word[a_pos].token_head.get();
+        if b_pos >= word.len() {
+            return None;
+        }
+        let pair = ((word[a_pos].piece.get(), word[b_pos].piece.get()), a_pos);
+        a_pos = b_pos;
+        Some(pair)
+    })
+}
+
+/// A token the next merge could create, with the pairs that form it and
+/// their total number of occurrences.
+type Candidate<C> = (Vec<C>, Vec<(u32, u32)>, usize);
+
+/// Merge candidates allowed by `allow` and occurring at least `min_freq`
+/// times, best first.
+fn rank_candidates<C: Ord + Hash + Clone, S: BuildHasher + Default>(
+    pieces: &Pieces<C>,
+    pairs: impl Iterator<Item = ((u32, u32), usize)>,
+    min_freq: usize,
+    allow: impl Fn(&[C], &[C]) -> bool,
+) -> Vec<Candidate<C>> {
+    // Different splits of the same token count as one candidate.
+    let mut candidates = HashMap::<Vec<C>, (Vec<(u32, u32)>, usize), S>::default();
+    for (key, count) in pairs {
+        if !allow(pieces.get(key.0), pieces.get(key.1)) {
+            continue;
+        }
+        let token = [pieces.get(key.0), pieces.get(key.1)].concat();
+        let entry = candidates.entry(token).or_default();
+        entry.0.push(key);
+        entry.1 += count;
+    }
+
+    // Sorted by frequency, then with ties going to the lexicographically
+    // smallest token, so training does not depend on the HashMap iteration
+    // order.
+    let mut candidates = candidates
+        .into_iter()
+        .map(|(token, (keys, frequency))| (token, keys, frequency))
+        .filter(|(_, _, frequency)| *frequency >= min_freq)
+        .collect::<Vec<_>>();
+    candidates.sort_by(|(a, _, x), (b, _, y)| y.cmp(x).then_with(|| a.cmp(b)));
+    candidates
+}
+
+fn build_trie<'a, C: Ord + Clone + 'a>(tokens: impl IntoIterator<Item = &'a Vec<C>>) -> Trie<C> {
+    // trie-rs loses a token pushed after a longer token it prefixes, so push
+    // in sorted order.
+    let mut sorted = tokens.into_iter().collect::<Vec<_>>();
+    sorted.sort();
+    let mut builder = TrieBuilder::new();
+    for x in sorted {
+        builder.push(x)
+    }
+    builder.build()
+}
+
+/// Shorthand for `Vocab::new`, `Vocab::train` and `Vocab::build`.
+pub fn train<C, Words, Word>(words: Words, vocab_size: usize, min_freq: usize) -> Tokenizer<C>
+where
+    C: Ord + Hash + Clone,
+    Words: IntoIterator<Item = Word>,
+    Word: IntoIterator<Item = C>,
+{
+    let mut vocab = Vocab::new(words);
+    vocab.train(vocab_size, min_freq);
+    vocab.build()
+}
+
+impl<C: Ord + Hash + Clone> Tokenizer<C> {
+    /// Builds a tokenizer from an explicit token list, assigning ids in
+    /// order. Duplicates keep their first id.
+    pub fn from_tokens<Tokens>(tokens: Tokens) -> Result<Self, BuildError<C>>
+    where
+        Tokens: IntoIterator<Item = Vec<C>>,
+    {
+        let mut seen = HashSet::new();
+        let mut unique = Vec::new();
+        for token in tokens {
+            if token.is_empty() {
+                return Err(BuildError::EmptyToken);
+            }
+            if seen.insert(token.clone()) {
+                unique.push(token);
+            }
+        }
+        Ok(Self::from_ordered(unique))
+    }
+
+    fn from_ordered(tokens: Vec<Vec<C>>) -> Self {
+        Self::from_parts(
+            vec!["<unk>".to_string()],
+            0,
+            tokens.into_iter().map(Some).collect(),
+        )
+    }
+
+    fn from_parts(specials: Vec<String>, unk_id: usize, tokens: Vec<Option<Vec<C>>>) -> Self {
+        let ids = tokens
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| Some((x.clone()?, specials.len() + i)))
+            .collect();
+        Tokenizer {
+            matcher: build_trie(tokens.iter().flatten()),
+            unk_id,
+            specials,
+            tokens,
+            ids,
+            normalizer: None,
+            protected: Vec::new(),
+            protected_trie: None,
+            frequencies: HashMap::new(),
+            splits: HashMap::new(),
+            byte_fallback: None,
+            end_of_word: None,
+            merge_ranked: false,
+        }
+    }
+
+    /// A tokenizer with the given tokens and every other setting of `self`.
+    fn with_tokens(&self, tokens: Vec<Option<Vec<C>>>) -> Self {
+        self.with_parts(self.specials.clone(), tokens)
+    }
+
+    /// Like `with_tokens`, also replacing the special tokens.
+    fn with_parts(&self, specials: Vec<String>, tokens: Vec<Option<Vec<C>>>) -> Self {
+        let mut result = Self::from_parts(specials, self.unk_id, tokens);
+        result.normalizer = self.normalizer.clone();
+        result.with_protected(self.protected.clone());
+        result.frequencies = self.frequencies.clone();
+        result.splits = self.splits.clone();
+        result.byte_fallback = self.byte_fallback;
+        result.end_of_word = self.end_of_word.clone();
+        result.merge_ranked = self.merge_ranked;
+        result
+    }
+
+    /// Reserves ids for special tokens such as `<pad>`, `<bos>` and `<eos>`
+    /// after the existing ones, which keep their ids. Names that are already
+    /// special are skipped. The ids of learned tokens shift up by the number
+    /// of names added.
+    pub fn with_special_tokens<Names>(&self, names: Names) -> Self
+    where
+        Names: IntoIterator,
+        Names::Item: Into<String>,
+    {
+        let mut specials = self.specials.clone();
+        for name in names {
+            let name = name.into();
+            if !specials.contains(&name) {
+                specials.push(name);
+            }
+        }
+        self.with_parts(specials, self.tokens.clone())
+    }
+
+    /// Builds a tokenizer containing the tokens of both. Ids of `self` are
+    /// preserved and tokens only in `other` are appended in `other`'s id
+    /// order. Special tokens and the normalizer are taken from `self`.
+    pub fn union(&self, other: &Tokenizer<C>) -> Tokenizer<C> {
+        let mut tokens = self.tokens.clone();
+        tokens.extend(
+            other
+                .tokens
+                .iter()
+                .flatten()
+                .filter(|x| !self.ids.contains_key(*x))
+                .cloned()
+                .map(Some),
+        );
+        let mut result = self.with_tokens(tokens);
+        for (token, frequency) in &other.frequencies {
+            result
+                .frequencies
+                .entry(token.clone())
+                .or_insert(*frequency);
+        }
+        for (token, split) in &other.splits {
+            result.splits.entry(token.clone()).or_insert(*split);
+        }
+        result
+    }
+
+    /// Removes every token for which `keep` returns false. The ids of the
+    /// remaining tokens are unchanged, so the removed ids become unused.
+    /// With merge ranks, a removed token also keeps every longer token
+    /// merged from it from forming.
+    pub fn prune(&mut self, keep: impl Fn(&[C]) -> bool) {
+        for slot in &mut self.tokens {
+            if slot.as_ref().is_some_and(|x| !keep(x)) {
+                self.ids.remove(slot.take().as_ref().unwrap());
+            }
+        }
+        self.matcher = build_trie(self.tokens.iter().flatten());
+    }
+
+    /// A tokenizer with only the tokens used to tokenize `words`, plus every
+    /// single-symbol token so no symbol loses its coverage. It segments
+    /// `words` exactly like `self`. Ids are renumbered densely in the
+    /// original order; other settings are kept. With merge ranks, the tokens
+    /// merged on the way to the used ones are kept as well.
+    pub fn restrict_to_corpus(&self, words: &[Vec<C>]) -> Tokenizer<C> {
+        let mut used = HashSet::new();
+        for word in words {
+            for piece in self.tokenize(word) {
+                // The normalized form is what matches, the piece as written
+                // can be what `encode` picks.
+                used.insert(self.normalize(piece).into_owned());
+                used.insert(piece.to_vec());
+            }
+            if self.merge_ranked {
+                let normalized = self.normalize(word);
+                let mut pos = 0;
+                let spans = self.protected_spans(word);
+                for (start, len) in spans.into_iter().chain([(word.len(), 0)]) {
+                    self.ranked_lengths_with(&normalized[pos..start], |x| {
+                        used.insert(x.to_vec());
+                    });
+                    pos = start + len;
+                }
+            }
+        }
+        let tokens = self
+            .tokens
+            .iter()
+            .flatten()
+            .filter(|x| x.len() == 1 || used.contains(*x))
+            .cloned()
+            .map(Some)
+            .collect();
+        self.with_tokens(tokens)
+    }
+
+    /// Panics unless tokenizing is a deterministic function of the token
+    /// set: every token and every concatenation of two tokens adjacent in id
+    /// order must tokenize the same twice in a row, and the same as with a
+    /// tokenizer whose trie is rebuilt from `tokens_sorted`. Meant for tests.
+    pub fn self_check(&self) {
+        let mut rebuilt = self.with_tokens(self.tokens.clone());
+        let mut builder = TrieBuilder::new();
+        for token in self.tokens_sorted() {
+            builder.push(token);
+        }
+        rebuilt.matcher = builder.build();
+
+        let tokens = self.tokens.iter().flatten().collect::<Vec<_>>();
+        let pairs = tokens.windows(2).map(|x| [&x[0][..], &x[1][..]].concat());
+        for word in tokens.iter().map(|x| x.to_vec()).chain(pairs) {
+            let tokens = self.tokenize(&word);
+            assert!(
+                tokens == self.tokenize(&word),
+                "tokenize is not deterministic"
+            );
+            assert!(
+                tokens == rebuilt.tokenize(&word),
+                "rebuilt trie tokenizes differently"
+            );
+        }
+    }
+
+    /// Adds the tokens `is_lossless` needs for `corpus`: every piece that
+    /// does not decode back to itself, because it is unknown or only matches
+    /// after normalization, becomes a token of its own. `encode` prefers
+    /// those exact tokens. With a normalizer that maps its outputs to
+    /// themselves, a new token only matches where a single-symbol fallback
+    /// was taken before, so segmentation is unchanged. New tokens get ids
+    /// after the existing ones.
+    pub fn ensure_lossless(&mut self, corpus: &[Vec<C>]) {
+        let mut tokens = self.tokens.clone();
+        let mut added = HashSet::new();
+        for word in corpus {
+            for piece in self.tokenize(word) {
+                let mut ids = Vec::new();
+                self.push_ids(piece, &mut ids);
+                if self.decode(&ids).as_deref() != Ok(piece) && added.insert(piece) {
+                    tokens.push(Some(piece.to_vec()));
+                }
+            }
+        }
+        if !added.is_empty() {
+            *self = self.with_tokens(tokens);
+        }
+    }
+
+    /// Renumbers the tokens densely, keeping their relative order. The
+    /// returned mapping gives the new id for every old id, or `None` for an
+    /// unused one.
+    pub fn compact_ids(&self) -> (Tokenizer<C>, Vec<Option<usize>>) {
+        let mut mapping = (0..self.specials.len()).map(Some).collect::<Vec<_>>();
+        let mut next = self.specials.len();
+        for slot in &self.tokens {
+            mapping.push(slot.as_ref().map(|_| {
+                next += 1;
+                next - 1
+            }));
+        }
+
+        let tokens = self.tokens.iter().flatten().cloned().map(Some).collect();
+        (self.with_tokens(tokens), mapping)
+    }
+}
+
+impl<C: Ord + Hash + Clone, M: PrefixMatcher<C>> Tokenizer<C, M> {
+    /// Replaces the trie with another matcher, which must find the same
+    /// tokens. Rebuilding methods such as `prune` are only available with
+    /// the default trie.
+    pub fn with_matcher<N: PrefixMatcher<C>>(self, matcher: N) -> Tokenizer<C, N> {
+        Tokenizer {
+            matcher,
+            specials: self.specials,
+            tokens: self.tokens,
+            ids: self.ids,
+            unk_id: self.unk_id,
+            normalizer: self.normalizer,
+            protected: self.protected,
+            protected_trie: self.protected_trie,
+            frequencies: self.frequencies,
+            splits: self.splits,
+            byte_fallback: self.byte_fallback,
+            end_of_word: self.end_of_word,
+            merge_ranked: self.merge_ranked,
+        }
+    }
+
+    /// Matches input symbols against the vocabulary after mapping them with
+    /// `normalizer`, while still returning slices of the original input.
+    pub fn with_normalizer(mut self, normalizer: impl Fn(&C) -> C + Send + Sync + 'static) -> Self {
+        self.normalizer = Some(Arc::new(normalizer));
+        self
+    }
+
+    /// Segments each word the way training did instead of by greedy longest
+    /// match: starting from single symbols, the adjacent pieces forming the
+    /// earliest learned token are joined, leftmost first, until no learned
+    /// token can be formed. The merge order is the id order, as `build`
+    /// assigns it. This matches other BPE implementations but takes time
+    /// quadratic in the word length, so input should be split into words.
+    pub fn with_merge_ranks(mut self) -> Self {
+        self.merge_ranked = true;
+        self
+    }
+
+    /// Emits occurrences of `protected` as single tokens, tokenizing only the
+    /// text between them. Protected tokens are matched against the raw input,
+    /// leftmost first and longest on overlap, and get ids after the existing
+    /// tokens if they are not already in the vocabulary.
+    pub fn with_protected(&mut self, protected: Vec<Vec<C>>) {
+        let protected = protected
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        for token in &protected {
+            if !self.ids.contains_key(token) {
+                self.ids.insert(token.clone(), self.vocab_size());
+                self.tokens.push(Some(token.clone()));
+            }
+        }
+
+        self.protected_trie = (!protected.is_empty()).then(|| build_trie(&protected));
+        self.protected = protected;
+    }
+
+    /// Non-overlapping `(start, len)` occurrences of protected tokens.
+    fn protected_spans(&self, word: &[C]) -> Vec<(usize, usize)> {
+        let Some(trie) = &self.protected_trie else {
+            return Vec::new();
+        };
+        let mut result = Vec::new();
+        let mut pos = 0;
+        while pos < word.len() {
+            let n = trie
+                .common_prefix_search(&word[pos..])
+                .into_iter()
+                .map(|x| x.len())
+                .max();
+            match n {
+                Some(n) => {
+                    result.push((pos, n));
+                    pos += n;
+                }
+                None => pos += 1,
+            }
+        }
+        result
+    }
+
+    /// Runs the greedy longest-match loop, calling `f(pos, len, window)` for
+    /// each token. `window` is the normalized input the token was matched
+    /// in, or `None` for a protected token.
+    fn walk(&self, word: &[C], f: impl FnMut(usize, usize, Option<&[C]>)) {
+        if !self.merge_ranked {
+            return self.walk_with(word, |window| self.longest_prefix(window), f);
+        }
+        // The first window of each run between protected tokens is the
+        // whole run, and its lengths are used up before the next run starts.
+        let mut lengths = Vec::new();
+        let choose = |window: &[C]| {
+            if lengths.is_empty() {
+                lengths = self.ranked_lengths(window);
+                lengths.reverse();
+            }
+            lengths.pop().unwrap()
+        };
+        self.walk_with(word, choose, f)
+    }
+
+    /// Token lengths of `window`, which must already be normalized, after
+    /// replaying the merges in id order.
+    fn ranked_lengths(&self, window: &[C]) -> Vec<usize> {
+        self.ranked_lengths_with(window, |_| {})
+    }
+
+    /// Like `ranked_lengths`, calling `on_merge` with every token formed on
+    /// the way, including those merged again later.
+    fn ranked_lengths_with(&self, window: &[C], mut on_merge: impl FnMut(&[C])) -> Vec<usize> {
+        let mut lengths = vec![1; window.len()];
+        loop {
+            let mut best = None::<(usize, usize)>;
+            let mut start = 0;
+            for i in 1..lengths.len() {
+                let merged = &window[start..start + lengths[i - 1] + lengths[i]];
+                if self.splits.contains_key(merged) {
+                    let rank = self.token_to_id(merged);
+                    if let Some(rank) = rank.filter(|&r| best.is_none_or(|(x, _)| r < x)) {
+                        best = Some((rank, i));
+                    }
+                }
+                start += lengths[i - 1];
+            }
+            let Some((_, i)) = best else {
+                return lengths;
+            };
+            let start = lengths[..i - 1].iter().sum::<usize>();
+            on_merge(&window[start..start + lengths[i - 1] + lengths[i]]);
+            lengths[i - 1] += lengths.remove(i);
+        }
+    }
+
+    /// Like `walk`, but `choose(window)` picks the length of each token.
+    fn walk_with(
+        &self,
+        word: &[C],
+        mut choose: impl FnMut(&[C]) -> usize,
+        mut f: impl FnMut(usize, usize, Option<&[C]>),
+    ) {
+        let normalized = self.normalize(word);
+        let mut pos = 0;
+        let spans = self.protected_spans(word);
+        for (start, len) in spans.into_iter().chain([(word.len(), 0)]) {
+            while pos < start {
+                let window = &normalized[pos..start];
+                let n = choose(window);
+                // Matches are prefixes of `window`, so this can only fail if
+                // `choose` is wrong; it would otherwise panic while slicing.
+                debug_assert!(0 < n && n <= window.len());
+                f(pos, n, Some(window));
+                pos += n;
+            }
+            if len > 0 {
+                f(pos, len, None);
+                pos += len;
+            }
+        }
+    }
+
+    fn normalize<'a>(&self, word: &'a [C]) -> Cow<'a, [C]> {
+        match &self.normalizer {
+            Some(f) => Cow::Owned(word.iter().map(|x| f(x)).collect()),
+            None => Cow::Borrowed(word),
+        }
+    }
+
+    /// Lengths of all vocabulary tokens that are a prefix of `word`, which
+    /// must already be normalized.
+    fn prefix_lengths(&self, word: &[C]) -> Vec<usize> {
+        self.matcher.common_prefix_lengths(word)
+    }
+
+    /// Length of the token `tokenize` takes at the start of `word`, falling
+    /// back to a single symbol.
+    fn longest_prefix(&self, word: &[C]) -> usize {
+        self.prefix_lengths(word).into_iter().max().unwrap_or(1)
+    }
+
+    /// A piece can correspond to several tokens once the normalizer maps
+    /// different tokens to the same symbols. The token equal to the piece as
+    /// written wins, then the token equal to its normalized form.
+    fn piece_id(&self, piece: &[C]) -> Option<usize> {
+        match self.normalizer {
+            Some(_) => self
+                .token_to_id(piece)
+                .or_else(|| self.token_to_id(&self.normalize(piece))),
+            None => self.token_to_id(piece),
+        }
+    }
+
+    pub fn tokenize<'a>(&self, word: &'a [C]) -> Vec<&'a [C]> {
+        let mut result = Vec::new();
+        self.walk(word, |pos, n, _| result.push(&word[pos..pos + n]));
+        result
+    }
+
+    /// Like `tokenize`, but a run of consecutive symbols without a token is
+    /// returned as one slice, so it stands for a single `unk_id`.
+    pub fn tokenize_coalesce_unk<'a>(&self, word: &'a [C]) -> Vec<&'a [C]> {
+        let mut result = Vec::<&[C]>::new();
+        let mut unk_start = None;
+        self.walk(word, |pos, n, _| {
+            let piece = &word[pos..pos + n];
+            if self.piece_id(piece).is_some() {
+                unk_start = None;
+                result.push(piece);
+            } else if let Some(start) = unk_start {
+                *result.last_mut().unwrap() = &word[start..pos + n];
+            } else {
+                unk_start = Some(pos);
+                result.push(piece);
+            }
+        });
+        result
+    }
+
+    /// Number of tokens `tokenize` would return, without collecting them.
+    pub fn count_tokens(&self, word: &[C]) -> usize {
+        let mut count = 0;
+        self.walk(word, |_, _, _| count += 1);
+        count
+    }
+
+    /// Encodes every word and returns the `k` most used tokens with their
+    /// counts, most frequent first. Ties are ordered by token. Counts are
+    /// per id, so pieces a normalizer maps to the same token count together,
+    /// and unknown pieces and special ids are not counted.
+    pub fn top_tokens(&self, words: &[Vec<C>], k: usize) -> Vec<(Vec<C>, usize)> {
+        let mut ids = HashMap::<usize, usize>::new();
+        for word in words {
+            for id in self.encode(word) {
+                *ids.entry(id).or_default() += 1;
+            }
+        }
+        let mut counts = ids
+            .into_iter()
+            .filter_map(|(id, n)| Some((self.id_to_token(id)?, n)))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        counts
+            .into_iter()
+            .take(k)
+            .map(|(x, n)| (x.to_vec(), n))
+            .collect()
+    }
+
+    /// Counts every run of `n` consecutive ids in the encoding of each word.
+    /// N-grams do not cross word boundaries.
+    pub fn token_ngram_counts(&self, words: &[Vec<C>], n: usize) -> HashMap<Vec<usize>, usize> {
+        let mut counts = HashMap::new();
+        if n == 0 {
+            return counts;
+        }
+        for word in words {
+            for ngram in self.encode(word).windows(n) {
+                *counts.entry(ngram.to_vec()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Runs the `tokenize` loop and records every step it takes. A protected
+    /// token is recorded as its only candidate.
+    pub fn trace(&self, word: &[C]) -> Vec<TraceStep> {
+        let mut result = Vec::new();
+        self.walk(word, |pos, n, window| {
+            let mut candidates = match window {
+                Some(window) => self.prefix_lengths(window),
+                None => vec![n],
+            };
+            candidates.sort();
+            result.push(TraceStep {
+                position: pos,
+                candidates,
+                chosen: n,
+            });
+        });
+        result
+    }
+
+    /// Like `tokenize`, but picks among the matching tokens by
+    /// `len + weight * ln(frequency)`, where `frequency` is the count the
+    /// token had when it was merged. Tokens without a recorded frequency,
+    /// including the single-symbol fallback, count as frequency 1. A
+    /// `weight` of 0 reproduces `tokenize`.
+    pub fn tokenize_freq_preferred<'a>(&self, word: &'a [C], weight: f64) -> Vec<&'a [C]> {
+        let score = |window: &[C], n: usize| {
+            let frequency = self.frequencies.get(&window[..n]).copied().unwrap_or(1);
+            n as f64 + weight * (frequency as f64).ln()
+        };
+        let choose = |window: &[C]| {
+            self.prefix_lengths(window)
+                .into_iter()
+                .chain([1])
+                .max_by(|&a, &b| {
+                    score(window, a)
+                        .total_cmp(&score(window, b))
+                        .then(a.cmp(&b))
+                })
+                .unwrap()
+        };
+        let mut result = Vec::new();
+        self.walk_with(word, choose, |pos, n, _| result.push(&word[pos..pos + n]));
+        result
+    }
+
+    /// BPE-dropout: like `tokenize`, but every matching token longer than one
+    /// symbol is dropped with probability `p` at each step. The same `seed`
+    /// always gives the same segmentation.
+    pub fn tokenize_dropout<'a>(&self, word: &'a [C], p: f64, seed: u64) -> Vec<&'a [C]> {
+        self.dropout_with(word, p, &mut Rng::new(seed))
+    }
+
+    /// `n` segmentations sampled with `tokenize_dropout`, reproducible from
+    /// `seed`. Samples may repeat.
+    pub fn sample_segmentations(
+        &self,
+        word: &[C],
+        n: usize,
+        p: f64,
+        seed: u64,
+    ) -> Vec<Vec<Vec<C>>> {
+        let mut rng = Rng::new(seed);
+        (0..n)
+            .map(|_| {
+                self.dropout_with(word, p, &mut rng)
+                    .into_iter()
+                    .map(|x| x.to_vec())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn dropout_with<'a>(&self, word: &'a [C], p: f64, rng: &mut Rng) -> Vec<&'a [C]> {
+        let choose = |window: &[C]| {
+            let mut candidates = self.prefix_lengths(window);
+            candidates.sort();
+            candidates
+                .into_iter()
+                .filter(|&n| n == 1 || rng.next_f64() >= p)
+                .max()
+                .unwrap_or(1)
+        };
+        let mut result = Vec::new();
+        self.walk_with(word, choose, |pos, n, _| result.push(&word[pos..pos + n]));
+        result
+    }
+
+    /// Every way to split `word` into vocabulary tokens and single symbols,
+    /// stopping after `max_results`. Protected tokens are never split.
+    /// Longer tokens are tried first, so the first result is the one
+    /// `tokenize` gives. The number of segmentations grows exponentially
+    /// with the word length in the worst case, so keep `max_results` small
+    /// for long words.
+    pub fn all_segmentations<'a>(&self, word: &'a [C], max_results: usize) -> Vec<Vec<&'a [C]>> {
+        let normalized = self.normalize(word);
+        let mut options = vec![Vec::new(); word.len()];
+        let mut pos = 0;
+        let spans = self.protected_spans(word);
+        for (start, len) in spans.into_iter().chain([(word.len(), 0)]) {
+            for (i, lengths) in options.iter_mut().enumerate().take(start).skip(pos) {
+                let mut matches = self.prefix_lengths(&normalized[i..start]);
+                matches.push(1);
+                matches.sort_unstable_by(|a, b| b.cmp(a));
+                matches.dedup();
+                *lengths = matches;
+            }
+            if len > 0 {
+                options[start] = vec![len];
+            }
+            pos = start + len;
+        }
+
+        fn extend<'a, C>(
+            word: &'a [C],
+            options: &[Vec<usize>],
+            pos: usize,
+            current: &mut Vec<&'a [C]>,
+            result: &mut Vec<Vec<&'a [C]>>,
+            max_results: usize,
+        ) {
+            if pos == word.len() {
+                result.push(current.clone());
+                return;
+            }
+            for &n in &options[pos] {
+                if result.len() >= max_results {
+                    return;
+                }
+                current.push(&word[pos..pos + n]);
+                extend(word, options, pos + n, current, result, max_results);
+                current.pop();
+            }
+        }
+        let mut result = Vec::new();
+        if max_results > 0 {
+            extend(word, &options, 0, &mut Vec::new(), &mut result, max_results);
+        }
+        result
+    }
+
+    /// Like `tokenize`, but consumes the word and returns owned tokens.
+    pub fn tokenize_owned(&self, word: Vec<C>) -> Vec<Vec<C>> {
+        self.tokenize(&word)
+            .into_iter()
+            .map(|x| x.to_vec())
+            .collect()
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.specials.len() + self.tokens.len()
+    }
+
+    /// Length of the longest token, or 1 for an empty vocabulary since
+    /// `tokenize` always falls back to single symbols.
+    pub fn max_token_len(&self) -> usize {
+        self.tokens
+            .iter()
+            .flatten()
+            .map(|x| x.len())
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Mean length of the learned tokens, not weighted by usage, or 0 for an
+    /// empty vocabulary. Special tokens are not counted.
+    pub fn average_token_length(&self) -> f64 {
+        let (count, total) = self
+            .tokens
+            .iter()
+            .flatten()
+            .fold((0, 0), |(n, sum), x| (n + 1, sum + x.len()));
+        if count == 0 {
+            0.0
+        } else {
+            total as f64 / count as f64
+        }
+    }
+
+    /// Size of the intersection over the size of the union of the learned
+    /// token sets, ignoring ids and special tokens. Two empty vocabularies
+    /// are identical.
+    pub fn jaccard<N>(&self, other: &Tokenizer<C, N>) -> f64 {
+        let union = self.ids.len() + other.ids.len();
+        let intersection = self
+            .ids
+            .keys()
+            .filter(|x| other.ids.contains_key(*x))
+            .count();
+        if union == 0 {
+            1.0
+        } else {
+            intersection as f64 / (union - intersection) as f64
+        }
+    }
+
+    /// The learned tokens in lexicographic order, independent of ids.
+    pub fn tokens_sorted(&self) -> Vec<&[C]> {
+        let mut tokens = self
+            .tokens
+            .iter()
+            .flatten()
+            .map(|x| &x[..])
+            .collect::<Vec<_>>();
+        tokens.sort();
+        tokens
+    }
+
+    pub fn unk_id(&self) -> usize {
+        self.unk_id
+    }
+
+    /// The id of the special token called `name`, see `with_special_tokens`.
+    pub fn special_id(&self, name: &str) -> Option<usize> {
+        self.specials.iter().position(|x| x == name)
+    }
+
+    pub fn token_to_id(&self, token: &[C]) -> Option<usize> {
+        self.ids.get(token).copied()
+    }
+
+    /// Returns `None` for special ids and ids outside the vocabulary.
+    pub fn id_to_token(&self, id: usize) -> Option<&[C]> {
+        let index = id.checked_sub(self.specials.len())?;
+        self.tokens.get(index)?.as_deref()
+    }
+
+    /// Pieces that are not in the vocabulary are encoded as `unk_id`, or as
+    /// the byte ids of each symbol in byte fallback mode. With a normalizer,
+    /// a piece gets the id of the token spelled exactly like it if there is
+    /// one, and otherwise that of its normalized form.
+    pub fn encode(&self, word: &[C]) -> Vec<usize> {
+        let mut result = Vec::new();
+        for piece in self.tokenize(word) {
+            self.push_ids(piece, &mut result);
+        }
+        result
+    }
+
+    /// Like `encode`, but rejects inputs of more than `max_input` symbols
+    /// before doing any work.
+    pub fn encode_checked(&self, word: &[C], max_input: usize) -> Result<Vec<usize>, EncodeError> {
+        if word.len() > max_input {
+            return Err(EncodeError::InputTooLong {
+                len: word.len(),
+                max: max_input,
+            });
+        }
+        Ok(self.encode(word))
+    }
+
+    /// Encodes every word into exactly `max_len` ids, truncating long ones
+    /// and right-padding short ones with `pad_id`. Also returns the length
+    /// of each encoding before padding, capped at `max_len`, for masking.
+    pub fn encode_batch_padded(
+        &self,
+        words: &[Vec<C>],
+        max_len: usize,
+        pad_id: usize,
+    ) -> (Vec<Vec<usize>>, Vec<usize>) {
+        words
+            .iter()
+            .map(|word| {
+                let mut ids = self.encode(word);
+                ids.truncate(max_len);
+                let len = ids.len();
+                ids.resize(max_len, pad_id);
+                (ids, len)
+            })
+            .unzip()
+    }
+
+    /// Whether every word of `corpus` decodes back to itself after
+    /// `encode`. This fails for symbols without a token, and with a
+    /// normalizer for pieces that only match a token after normalization.
+    pub fn is_lossless(&self, corpus: &[Vec<C>]) -> bool {
+        corpus
+            .iter()
+            .all(|word| self.decode(&self.encode(word)).as_ref() == Ok(word))
+    }
+
+    /// Looks up an existing segmentation instead of computing one, giving
+    /// `None` for tokens that are not in the vocabulary. Tokens that only
+    /// match after normalization are found as in `encode`.
+    pub fn ids_for_tokens(&self, tokens: &[Vec<C>]) -> Vec<Option<usize>> {
+        tokens.iter().map(|x| self.piece_id(x)).collect()
+    }
+
+    /// Like `encode`, with the `(start, end, id)` range of `word` each id
+    /// came from. Byte fallback ids all get the range of their piece.
+    pub fn encode_with_offsets(&self, word: &[C]) -> Vec<(usize, usize, usize)> {
+        let mut result = Vec::new();
+        let mut ids = Vec::new();
+        self.walk(word, |pos, n, _| {
+            self.push_ids(&word[pos..pos + n], &mut ids);
+            result.extend(ids.drain(..).map(|id| (pos, pos + n, id)));
+        });
+        result
+    }
+
+    /// `encode` together with the symbols of each id, in one pass. In byte
+    /// fallback mode the first byte id of a symbol carries the symbol and
+    /// its other byte ids carry nothing, so the pieces still concatenate to
+    /// `word`.
+    pub fn encode_with_pieces(&self, word: &[C]) -> Vec<(usize, Vec<C>)> {
+        let mut result = Vec::new();
+        let mut ids = Vec::new();
+        self.walk(word, |pos, n, _| {
+            let piece = &word[pos..pos + n];
+            if let (None, Some(fallback)) = (self.piece_id(piece), &self.byte_fallback) {
+                for c in piece {
+                    let bytes = (fallback.to_bytes)(c);
+                    for (i, b) in bytes.into_iter().enumerate() {
+                        let symbols = if i == 0 { vec![c.clone()] } else { Vec::new() };
+                        result.push((fallback.first_id + b as usize, symbols));
+                    }
+                }
+                return;
+            }
+            self.push_ids(piece, &mut ids);
+            result.extend(ids.drain(..).map(|id| (id, piece.to_vec())));
+        });
+        result
+    }
+
+    fn push_ids(&self, piece: &[C], result: &mut Vec<usize>) {
+        match (self.piece_id(piece), &self.byte_fallback) {
+            (Some(id), _) => result.push(id),
+            (None, Some(fallback)) => {
+                for c in piece {
+                    let bytes = (fallback.to_bytes)(c);
+                    result.extend(bytes.into_iter().map(|b| fallback.first_id + b as usize));
+                }
+            }
+            (None, None) => result.push(self.unk_id),
+        }
+    }
+
+    /// Special ids, including `unk_id`, decode to nothing, except byte
+    /// fallback ids, whose consecutive runs are decoded back to symbols.
+    pub fn decode(&self, ids: &[usize]) -> Result<Vec<C>, DecodeError> {
+        let mut result = Vec::new();
+        let mut bytes = Vec::new();
+        for &id in ids {
+            if let Some(b) = self.fallback_byte(id) {
+                bytes.push(b);
+                continue;
+            }
+            self.flush_bytes(&mut bytes, &mut result)?;
+            if id < self.specials.len() {
+                continue;
+            }
+            let token = self.id_to_token(id).ok_or(DecodeError::InvalidId(id))?;
+            result.extend_from_slice(token);
+        }
+        self.flush_bytes(&mut bytes, &mut result)?;
+        Ok(result)
+    }
+
+    /// `encode` with `u32` ids, the width most model code expects. Panics if
+    /// an id does not fit, which takes more than `u32::MAX` ids.
+    pub fn encode_u32(&self, word: &[C]) -> Vec<u32> {
+        self.encode(word)
+            .into_iter()
+            .map(|id| u32::try_from(id).expect("token id does not fit in u32"))
+            .collect()
+    }
+
+    /// `decode` with `u32` ids, see `encode_u32`.
+    pub fn decode_u32(&self, ids: &[u32]) -> Result<Vec<C>, DecodeError> {
+        self.decode(&ids.iter().map(|&id| id as usize).collect::<Vec<_>>())
+    }
+
+    /// The marker the vocabulary was trained with, see
+    /// `Vocab::with_end_of_word`.
+    pub fn end_of_word(&self) -> Option<&C> {
+        self.end_of_word.as_ref()
+    }
+
+    /// Encodes a sequence of words, appending the end-of-word marker to each
+    /// one if there is one.
+    pub fn encode_words<Words, Word>(&self, words: Words) -> Vec<usize>
+    where
+        Words: IntoIterator<Item = Word>,
+        Word: AsRef<[C]>,
+    {
+        let mut result = Vec::new();
+        for word in words {
+            let mut word = word.as_ref().to_vec();
+            word.extend(self.end_of_word.clone());
+            result.extend(self.encode(&word));
+        }
+        result
+    }
+
+    /// Decodes ids and splits the symbols into words at each end-of-word
+    /// marker, which is removed. Without a marker the result is one word.
+    pub fn decode_words(&self, ids: &[usize]) -> Result<Vec<Vec<C>>, DecodeError> {
+        let symbols = self.decode(ids)?;
+        let Some(marker) = &self.end_of_word else {
+            return Ok(vec![symbols]);
+        };
+        let mut words = symbols
+            .split(|c| c == marker)
+            .map(|x| x.to_vec())
+            .collect::<Vec<_>>();
+        if words.last().is_some_and(|x| x.is_empty()) {
+            words.pop();
+        }
+        Ok(words)
+    }
+
+    fn fallback_byte(&self, id: usize) -> Option<u8> {
+        let fallback = self.byte_fallback.as_ref()?;
+        u8::try_from(id.checked_sub(fallback.first_id)?).ok()
+    }
+
+    fn flush_bytes(&self, bytes: &mut Vec<u8>, result: &mut Vec<C>) -> Result<(), DecodeError> {
+        if let (Some(fallback), false) = (&self.byte_fallback, bytes.is_empty()) {
+            let symbols = (fallback.from_bytes)(bytes)
+                .ok_or_else(|| DecodeError::InvalidBytes(bytes.clone()))?;
+            result.extend(symbols);
+            bytes.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The words of `data.txt`, one per line.
+    pub(crate) fn data_words() -> Vec<Vec<char>> {
+        std::fs::read_to_string("data.txt")
+            .unwrap()
+            .lines()
+            .map(|x| x.chars().collect())
+            .collect()
+    }
+
+    #[test]
+    fn case01() {
+        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
+        let mut vocab = Vocab::new([data.clone()]);
+        for _ in 0..4 {
+            vocab.merge(1).unwrap();
+        }
+
+        let tokenizer = vocab.build();
+
+        let tokenized = tokenizer
+            .tokenize(&data)
+            .into_iter()
+            .map(|cs| cs.iter().copied().collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" ");
+        dbg!(tokenized);
+    }
+
+    #[test]
+    fn case02() {
+        let mut vocab = Vocab::new(data_words());
+
+        for _ in 0..100 {
+            if vocab.merge(2).is_err() {
+                break;
+            }
+        }
+
+        let tokenizer = vocab.build();
+
+        let test_words = data_words().into_iter().take(10).collect::<Vec<_>>();
+
+        for word in &test_words {
+            let tokens = tokenizer.tokenize(word);
+
+            let display = tokens
+                .into_iter()
+                .map(|cs| cs.iter().copied().collect::<String>())
+                .collect::<Vec<_>>()
+                .join(" ");
+            println!("{}", display)
+        }
+    }
+
+    #[test]
+    fn train_matches_manual_steps() {
+        let words =
+            ["banana", "bandana", "ananas", "cabana"].map(|w| w.chars().collect::<Vec<_>>());
+
+        let mut vocab = Vocab::new(words.clone());
+        vocab.train(3, 2);
+        assert_eq!(vocab.tokens().len(), 3);
+        let manual = vocab.build();
+
+        let trained = train(words.clone(), 3, 2);
+        for word in &words {
+            assert_eq!(trained.tokenize(word), manual.tokenize(word));
+        }
+    }
+
+    #[test]
+    fn merge_records_in_order() {
+        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
+        let mut vocab = Vocab::new([data]);
+        for _ in 0..3 {
+            vocab.merge(1).unwrap();
+        }
+
+        let records = vocab
+            .merge_records()
+            .map(|r| {
+                let render = |cs: &[char]| cs.iter().collect::<String>();
+                (render(r.left()), render(r.right()), r.frequency())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [
+                ("C".into(), "D".into(), 4),
+                ("A".into(), "B".into(), 2),
+                ("AB".into(), "CD".into(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_owned_matches_tokenize() {
+        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
+        let tokenizer = train([data.clone()], 4, 1);
+
+        let borrowed = tokenizer.tokenize(&data);
+        let owned = tokenizer.tokenize_owned(data.clone());
+        assert_eq!(owned, borrowed);
+    }
+
+    #[test]
+    fn decode_rejects_invalid_id() {
+        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
+        let tokenizer = train([data.clone()], 4, 1);
+        assert_eq!(tokenizer.vocab_size(), 5);
+
+        let ids = tokenizer.encode(&data);
+        assert!(ids.contains(&tokenizer.unk_id()));
+        let known = "ABCDCDABCDCD".chars().collect::<Vec<_>>();
+        assert_eq!(tokenizer.decode(&tokenizer.encode(&known)).unwrap(), known);
+
+        let past_end = tokenizer.vocab_size();
+        assert_eq!(
+            tokenizer.decode(&[1, past_end]),
+            Err(DecodeError::InvalidId(past_end))
+        );
+    }
+
+    #[test]
+    fn normalizer_matches_raw_input() {
+        let words = ["café", "cafés", "caféine"].map(|w| w.chars().collect::<Vec<_>>());
+        let tokenizer =
+            train(words, 100, 2).with_normalizer(|c: &char| c.to_lowercase().next().unwrap_or(*c));
+
+        let raw = "CAFÉ".chars().collect::<Vec<_>>();
+        let tokens = tokenizer.tokenize(&raw);
+        assert_eq!(tokens, [&raw[..]]);
+
+        let lower = "café".chars().collect::<Vec<_>>();
+        assert_eq!(tokenizer.encode(&raw), tokenizer.encode(&lower));
+        assert_ne!(tokenizer.encode(&raw), [tokenizer.unk_id()]);
+    }
+
+    #[test]
+    fn train_capped_stops_at_size() {
+        let mut vocab = Vocab::new(data_words());
+        vocab.train_capped(20);
+        assert_eq!(vocab.tokens().len(), 20);
+        assert!(vocab.merge_records().all(|r| r.frequency() >= 2));
+    }
+
+    #[test]
+    fn trace_covers_input() {
+        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
+        let tokenizer = train([data.clone()], 4, 1);
+
+        let trace = tokenizer.trace(&data);
+        assert_eq!(trace.iter().map(|x| x.chosen).sum::<usize>(), data.len());
+        assert_eq!(
+            trace.iter().map(|x| x.chosen).collect::<Vec<_>>(),
+            tokenizer
+                .tokenize(&data)
+                .iter()
+                .map(|x| x.len())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(trace.last().unwrap().candidates, Vec::<usize>::new());
+    }
+
+    #[test]
+    fn train_with_schedule_gates_merges() {
+        let words = data_words();
+
+        let mut fixed = Vocab::new(words.clone());
+        fixed.train(usize::MAX, 4);
+        let mut constant = Vocab::new(words.clone());
+        constant.train_with_schedule(|_| 4);
+        assert_eq!(constant.tokens(), fixed.tokens());
+
+        let mut decaying = Vocab::new(words);
+        decaying.train_with_schedule(|n| if n < 10 { 4 } else { 2 });
+        assert!(decaying.tokens().len() > fixed.tokens().len());
+    }
+
+    #[test]
+    fn union_covers_both() {
+        let a = train(["banana".chars().collect::<Vec<_>>()], 10, 1);
+        let b = train(["kiwi".chars().collect::<Vec<_>>()], 10, 1);
+        let union = a.union(&b);
+
+        assert_eq!(union.vocab_size(), a.vocab_size() + b.vocab_size() - 1);
+        for id in 0..a.vocab_size() {
+            assert_eq!(union.id_to_token(id), a.id_to_token(id));
+        }
+        for word in ["banana", "kiwi"] {
+            let word = word.chars().collect::<Vec<_>>();
+            assert_eq!(union.tokenize(&word), [&word[..]]);
+        }
+    }
+
+    #[test]
+    fn from_tokens_validates() {
+        let tokens = ["an", "ban", "an"].map(|w| w.chars().collect::<Vec<_>>());
+        let tokenizer = Tokenizer::from_tokens(tokens.clone()).unwrap();
+        assert_eq!(tokenizer.vocab_size(), 3);
+        assert_eq!(tokenizer.token_to_id(&tokens[0]), Some(1));
+
+        let with_empty = tokens.into_iter().chain([Vec::new()]);
+        assert_eq!(
+            Tokenizer::from_tokens(with_empty).err(),
+            Some(BuildError::EmptyToken)
+        );
+    }
+
+    #[test]
+    fn count_tokens_matches_tokenize() {
+        let words = data_words();
+        let tokenizer = train(words.clone(), 50, 2);
+
+        for word in words.iter().chain([&Vec::new()]) {
+            assert_eq!(tokenizer.count_tokens(word), tokenizer.tokenize(word).len());
+        }
+    }
+
+    #[test]
+    fn protected_tokens_are_not_split() {
+        let words = ["{{", "x}}", "a{{"].map(|w| w.chars().collect::<Vec<_>>());
+        let mut tokenizer = train(words.into_iter().cycle().take(6), 10, 2);
+
+        let placeholder = "{{x}}".chars().collect::<Vec<_>>();
+        let text = "a{{x}}b".chars().collect::<Vec<_>>();
+        assert_ne!(tokenizer.tokenize(&text)[1], &placeholder[..]);
+
+        tokenizer.with_protected(vec![placeholder.clone()]);
+        let tokens = tokenizer.tokenize(&text);
+        assert_eq!(tokens, [&text[..1], &placeholder[..], &text[6..]]);
+        assert_eq!(tokenizer.count_tokens(&text), 3);
+
+        let id = tokenizer.token_to_id(&placeholder).unwrap();
+        assert_eq!(tokenizer.encode(&text)[1], id);
+        assert_eq!(
+            tokenizer.decode(&tokenizer.encode(&placeholder)).unwrap(),
+            placeholder
+        );
+    }
+
+    #[test]
+    fn compact_ids_after_prune() {
+        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
+        let mut tokenizer = train([data.clone()], 4, 1);
+        let before = tokenizer.vocab_size();
+        tokenizer.prune(|x| x.len() != 4);
+        assert_eq!(tokenizer.vocab_size(), before);
+
+        let (compacted, mapping) = tokenizer.compact_ids();
+        assert_eq!(mapping.len(), before);
+        assert_eq!(mapping.iter().flatten().count(), compacted.vocab_size());
+        for id in 0..compacted.vocab_size() {
+            assert_eq!(mapping.iter().filter(|x| **x == Some(id)).count(), 1);
+        }
+        for (old, new) in mapping.iter().enumerate() {
+            match new {
+                Some(new) => assert_eq!(compacted.id_to_token(*new), tokenizer.id_to_token(old)),
+                None => assert_eq!(tokenizer.id_to_token(old), None),
+            }
+        }
+        assert_eq!(compacted.tokenize(&data), tokenizer.tokenize(&data));
+
+        let unused = mapping.iter().position(|x| x.is_none()).unwrap();
+        assert_eq!(
+            tokenizer.decode(&[unused]),
+            Err(DecodeError::InvalidId(unused))
+        );
+    }
+
+    #[test]
+    fn tokenize_prefers_frequent_tokens() {
+        // "ab" is merged from many words, "abc" only from two.
+        let words = ["ab", "ab", "ab", "ab", "ab", "ab", "abc", "abc", "cd", "cd"]
+            .map(|w| w.chars().collect::<Vec<_>>());
+        let tokenizer = train(words, 10, 2);
+
+        let word = "abcd".chars().collect::<Vec<_>>();
+        let render = |tokens: Vec<&[char]>| {
+            tokens
+                .into_iter()
+                .map(|x| x.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(render(tokenizer.tokenize(&word)), ["abc", "d"]);
+        assert_eq!(
+            render(tokenizer.tokenize_freq_preferred(&word, 0.0)),
+            ["abc", "d"]
+        );
+        assert_eq!(
+            render(tokenizer.tokenize_freq_preferred(&word, 2.0)),
+            ["ab", "cd"]
+        );
+    }
+
+    #[test]
+    fn merge_filtered_respects_predicate() {
+        let words = ["a b", "a b", "a b", "ab"].map(|w| w.chars().collect::<Vec<_>>());
+        let mut vocab = Vocab::new(words);
+        while vocab.merge_filtered(1, |_, right| right[0] != ' ').is_ok() {}
+
+        assert!(!vocab.tokens().is_empty());
+        assert!(vocab.merge_records().all(|r| r.right()[0] != ' '));
+        assert!(vocab.tokens().contains(&vec![' ', 'b']));
+    }
+
+    #[test]
+    fn sampled_segmentations_are_reproducible() {
+        let words = data_words();
+        let tokenizer = train(words, 100, 2);
+        let word = "internationality".chars().collect::<Vec<_>>();
+
+        let a = tokenizer.sample_segmentations(&word, 20, 0.3, 42);
+        let b = tokenizer.sample_segmentations(&word, 20, 0.3, 42);
+        assert_eq!(a, b);
+        assert!(a.iter().all(|x| x.concat() == word));
+        assert!(a.iter().any(|x| x != &a[0]));
+
+        let greedy = tokenizer.tokenize_owned(word.clone());
+        assert_eq!(
+            tokenizer.sample_segmentations(&word, 3, 0.0, 1),
+            [greedy.clone(), greedy.clone(), greedy]
+        );
+        assert_eq!(tokenizer.tokenize_dropout(&word, 1.0, 7).len(), word.len());
+    }
+
+    #[test]
+    fn warm_start_keeps_tokens() {
+        let words = data_words();
+        let (old, new) = words.split_at(words.len() / 2);
+        let base = train(old.to_vec(), 30, 2);
+
+        let mut vocab = Vocab::from_tokenizer(&base, new.to_vec());
+        assert_eq!(vocab.tokens().len(), 30);
+        assert_eq!(vocab.merge_records().count(), 30);
+        vocab.train(60, 2);
+        assert_eq!(vocab.tokens().len(), 60);
+
+        let tokenizer = vocab.build();
+        for (token, frequency) in &base.frequencies {
+            assert_eq!(tokenizer.frequencies.get(token), Some(frequency));
+        }
+        let ranked = vocab.build().with_merge_ranks();
+        for token in base.tokens.iter().flatten() {
+            assert_eq!(ranked.tokenize(token), [&token[..]]);
+        }
+        for id in 0..base.vocab_size() {
+            assert_eq!(tokenizer.id_to_token(id), base.id_to_token(id));
+        }
+        for word in new {
+            assert!(tokenizer.count_tokens(word) <= base.count_tokens(word));
+        }
+    }
 
-        for word in &test_words {
-            let tokens = tokenizer.tokenize(&word);
+    #[test]
+    fn newtype_symbols() {
+        #[derive(Debug, Clone, Copy)]
+        struct Folded(char);
 
-            let display = tokens
-                .into_iter()
-                .map(|cs| cs.iter().copied().collect::<String>())
+        impl PartialEq for Folded {
+            fn eq(&self, other: &Self) -> bool {
+                self.0.eq_ignore_ascii_case(&other.0)
+            }
+        }
+        impl Eq for Folded {}
+        impl PartialOrd for Folded {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Folded {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                self.0
+                    .to_ascii_lowercase()
+                    .cmp(&other.0.to_ascii_lowercase())
+            }
+        }
+        impl Hash for Folded {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                self.0.to_ascii_lowercase().hash(state)
+            }
+        }
+
+        let symbols = |w: &str| w.chars().map(Folded).collect::<Vec<_>>();
+        let tokenizer = train(["Token", "TOKEN", "token"].map(symbols), 10, 2);
+        let upper = symbols("TOKEN");
+        assert_eq!(tokenizer.tokenize(&upper), [&upper[..]]);
+        assert_eq!(
+            tokenizer.encode(&upper),
+            tokenizer.encode(&symbols("tOkEn"))
+        );
+    }
+
+    #[test]
+    fn build_checked_reports_missing_symbols() {
+        let words = ["aab", "aab", "aac"].map(|w| w.chars().collect::<Vec<_>>());
+        let mut vocab = Vocab::new(words);
+        vocab.train(1, 2);
+        assert_eq!(
+            vocab.build_checked().err(),
+            Some(BuildError::IncompleteCoverage(vec!['a', 'b', 'c']))
+        );
+
+        let vocab = vocab.with_seed_tokens([vec!['a'], vec!['b']]);
+        assert_eq!(
+            vocab.build_checked().err(),
+            Some(BuildError::IncompleteCoverage(vec!['c']))
+        );
+
+        let vocab = vocab.with_seed_tokens([vec!['c']]);
+        let tokenizer = vocab.build_checked().unwrap();
+        let word = "aabc".chars().collect::<Vec<_>>();
+        assert!(!tokenizer.encode(&word).contains(&tokenizer.unk_id()));
+    }
+
+    #[test]
+    fn end_of_word_tokens() {
+        const EOW: char = '\u{e000}';
+        let text = "running fast singing ringing sing ring kings thing string";
+        let vocab = Vocab::with_end_of_word(text.split(' ').map(|w| w.chars()), EOW);
+        let mut vocab = vocab.with_seed_tokens(text.chars().chain([EOW]).map(|c| vec![c]));
+        vocab.train(60, 2);
+        let tokenizer = vocab.build();
+        assert_eq!(tokenizer.end_of_word(), Some(&EOW));
+
+        let internal = tokenizer.token_to_id(&['i', 'n', 'g']).unwrap();
+        let last = tokenizer.token_to_id(&['i', 'n', 'g', EOW]).unwrap();
+        assert_ne!(internal, last);
+
+        for sentence in ["running", "running fast"] {
+            let words = sentence
+                .split(' ')
+                .map(|w| w.chars().collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            let ids = tokenizer.encode_words(&words);
+            let decoded = tokenizer
+                .decode_words(&ids)
+                .unwrap()
+                .iter()
+                .map(|w| w.iter().collect::<String>())
                 .collect::<Vec<_>>()
                 .join(" ");
-            println!("{}", display)
+            assert_eq!(decoded, sentence);
+        }
+    }
+
+    #[test]
+    fn top_tokens_by_usage() {
+        let tokenizer =
+            Tokenizer::from_tokens(["ab", "c"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let words = ["ababab", "abc", "c"].map(|x| x.chars().collect::<Vec<_>>());
+        let top = tokenizer.top_tokens(&words, 1);
+        assert_eq!(top, [(vec!['a', 'b'], 4)]);
+        let top = tokenizer.top_tokens(&words, 5);
+        assert_eq!(top, [(vec!['a', 'b'], 4), (vec!['c'], 2)]);
+
+        // Unknown symbols are not tokens, and normalized variants count as
+        // the token they encode to.
+        let tokenizer = tokenizer.with_normalizer(char::to_ascii_lowercase);
+        let words = ["ABx", "ab", "C"].map(|x| x.chars().collect::<Vec<_>>());
+        let top = tokenizer.top_tokens(&words, 5);
+        assert_eq!(top, [(vec!['a', 'b'], 2), (vec!['c'], 1)]);
+    }
+
+    #[test]
+    fn whole_word_token() {
+        let data = "ABCDCDABCDCDE".chars().collect::<Vec<_>>();
+        let mut vocab = Vocab::new([data.clone()]);
+        while vocab.merge(1).is_ok() {}
+        let tokenizer = vocab.build();
+        assert_eq!(tokenizer.max_token_len(), data.len());
+        assert_eq!(tokenizer.tokenize(&data), [&data[..]]);
+
+        for n in 0..data.len() {
+            for word in [&data[..n], &data[n..]] {
+                let tokens = tokenizer.tokenize(word);
+                assert_eq!(tokens.concat(), word);
+            }
+        }
+    }
+
+    #[test]
+    fn coalesce_unknown_runs() {
+        let tokenizer =
+            Tokenizer::from_tokens(["ab", "c"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let word = "ab###xyzc?".chars().collect::<Vec<_>>();
+        assert_eq!(tokenizer.tokenize(&word).len(), 9);
+
+        let tokens = tokenizer
+            .tokenize_coalesce_unk(&word)
+            .into_iter()
+            .map(|x| x.iter().collect::<String>())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, ["ab", "###xyz", "c", "?"]);
+        let unk = tokenizer.unk_id();
+        let ids = tokenizer
+            .tokenize_coalesce_unk(&word)
+            .into_iter()
+            .map(|x| tokenizer.token_to_id(x).unwrap_or(unk))
+            .collect::<Vec<_>>();
+        assert_eq!(ids.iter().filter(|&&x| x == unk).count(), 2);
+    }
+
+    #[test]
+    fn training_independent_of_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let words = data_words();
+        let mut random = Vocab::new(words.clone());
+        let mut fixed = Vocab::new(words);
+        while random.merge_hashed::<RandomState>(2, |_, _| true).is_ok() {
+            fixed
+                .merge_hashed::<BuildHasherDefault<DefaultHasher>>(2, |_, _| true)
+                .unwrap();
+        }
+        assert!(fixed
+            .merge_hashed::<BuildHasherDefault<DefaultHasher>>(2, |_, _| true)
+            .is_err());
+        assert_eq!(random.order, fixed.order);
+        let merges = |v: &Vocab<char>| {
+            v.merges
+                .iter()
+                .map(|m| (m.token.clone(), m.split, m.frequency))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(merges(&random), merges(&fixed));
+
+        // The kept pair index behind `merge` and `train` agrees too.
+        let mut trained = Vocab::new(data_words());
+        trained.train(usize::MAX, 2);
+        let mut indexed = Vocab::new(data_words());
+        let mut index = PairIndex::<char, BuildHasherDefault<DefaultHasher>>::new(
+            &indexed.pieces,
+            indexed.weighted_words(),
+        );
+        while indexed.merge_indexed(&mut index, 2).is_some() {}
+        assert_eq!(merges(&trained), merges(&fixed));
+        assert_eq!(merges(&indexed), merges(&fixed));
+    }
+
+    #[test]
+    fn average_length() {
+        let tokens = ["a", "bc", "def", "ghij"].map(|x| x.chars().collect::<Vec<_>>());
+        let mut tokenizer = Tokenizer::from_tokens(tokens).unwrap();
+        assert_eq!(tokenizer.average_token_length(), 2.5);
+        tokenizer.prune(|x| x.len() <= 2);
+        assert_eq!(tokenizer.average_token_length(), 1.5);
+        assert_eq!(
+            Tokenizer::<char>::from_ordered(vec![]).average_token_length(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn custom_matcher() {
+        struct Linear(Vec<Vec<char>>);
+        impl PrefixMatcher<char> for Linear {
+            fn common_prefix_lengths(&self, input: &[char]) -> Vec<usize> {
+                self.0
+                    .iter()
+                    .filter(|x| input.starts_with(x))
+                    .map(|x| x.len())
+                    .collect()
+            }
+        }
+
+        let words = data_words();
+        let trie = train(words.clone(), 100, 2);
+        let tokens = (0..trie.vocab_size())
+            .filter_map(|id| trie.id_to_token(id))
+            .map(|x| x.to_vec())
+            .collect();
+        let linear = train(words.clone(), 100, 2).with_matcher(Linear(tokens));
+        for word in &words {
+            assert_eq!(linear.tokenize(word), trie.tokenize(word));
+            assert_eq!(linear.encode(word), trie.encode(word));
+        }
+    }
+
+    #[test]
+    fn can_merge_matches_merge() {
+        let mut vocab = Vocab::new(data_words());
+        for min_freq in [10, 3, 1] {
+            while vocab.can_merge(min_freq) {
+                vocab.merge(min_freq).unwrap();
+            }
+            assert!(vocab.merge(min_freq).is_err());
+        }
+        assert!(!vocab.can_merge(1));
+    }
+
+    #[test]
+    fn offsets_match_encode() {
+        let words = data_words();
+        let tokenizer = train(words.clone(), 100, 2);
+        for word in &words {
+            let offsets = tokenizer.encode_with_offsets(word);
+            let ids = offsets.iter().map(|x| x.2).collect::<Vec<_>>();
+            assert_eq!(ids, tokenizer.encode(word));
+
+            let mut pos = 0;
+            for &(start, end, id) in &offsets {
+                assert_eq!(start, pos);
+                if id != tokenizer.unk_id() {
+                    assert_eq!(tokenizer.id_to_token(id), Some(&word[start..end]));
+                }
+                pos = end;
+            }
+            assert_eq!(pos, word.len());
+        }
+    }
+
+    #[test]
+    fn sampled_training() {
+        let words = data_words();
+        let n = words.len();
+        let mut exact = Vocab::new(words.clone());
+        exact.train(80, 2);
+        let mut full = Vocab::new(words.clone());
+        full.train_sampled(80, 2, n, 7);
+        assert_eq!(exact.order, full.order);
+
+        let sampled = || {
+            let mut vocab = Vocab::new(words.clone());
+            vocab.train_sampled(80, 2, n / 4, 7);
+            vocab.order
+        };
+        assert_eq!(sampled(), sampled());
+        assert!(!sampled().is_empty());
+    }
+
+    #[test]
+    fn padded_batch() {
+        let tokenizer =
+            Tokenizer::from_tokens(["ab", "c"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let words = ["ab", "abcabc", ""].map(|x| x.chars().collect::<Vec<_>>());
+        let pad = 99;
+        let (ids, lens) = tokenizer.encode_batch_padded(&words, 3, pad);
+        assert_eq!(ids, [vec![1, pad, pad], vec![1, 2, 1], vec![pad; 3]]);
+        assert_eq!(lens, [1, 3, 0]);
+    }
+
+    #[test]
+    fn jaccard_similarity() {
+        let tokenizer = |x: &[&str]| {
+            Tokenizer::from_tokens(x.iter().map(|x| x.chars().collect::<Vec<_>>())).unwrap()
+        };
+        let a = tokenizer(&["ab", "cd", "ef"]);
+        assert_eq!(a.jaccard(&a), 1.0);
+        assert_eq!(a.jaccard(&tokenizer(&["gh", "ij"])), 0.0);
+        assert_eq!(a.jaccard(&tokenizer(&["ef", "cd", "gh"])), 0.5);
+    }
+
+    #[test]
+    fn early_stop_on_plateau() {
+        let words = [("ab", 5), ("cd", 4), ("ef", 3), ("gh", 2)]
+            .iter()
+            .flat_map(|&(w, n)| std::iter::repeat_n(w.chars().collect::<Vec<_>>(), n))
+            .collect::<Vec<_>>();
+        let val = vec![vec!['a', 'b']];
+
+        let mut vocab = Vocab::new(words.clone());
+        vocab.train_early_stop(&val, 2, 2);
+        assert_eq!(vocab.order.len(), 3);
+
+        let mut vocab = Vocab::new(words);
+        vocab.train_early_stop(&val, 10, 2);
+        assert_eq!(vocab.order.len(), 4);
+    }
+
+    #[test]
+    fn pair_frequencies_predict_merge() {
+        let mut vocab = Vocab::new(data_words());
+        for _ in 0..20 {
+            let frequencies = vocab.pair_frequencies(2);
+            assert!(frequencies.windows(2).all(|x| x[0].1 >= x[1].1));
+            vocab.merge(2).unwrap();
+            let record = vocab.merge_records().last().unwrap();
+            assert_eq!(
+                (record.token().to_vec(), record.frequency()),
+                frequencies[0]
+            );
+        }
+    }
+
+    #[test]
+    fn ids_for_given_tokens() {
+        let tokenizer =
+            Tokenizer::from_tokens(["ab", "c"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let tokens = ["c", "abc", "ab", "a"].map(|x| x.chars().collect::<Vec<_>>());
+        assert_eq!(
+            tokenizer.ids_for_tokens(&tokens),
+            [Some(2), None, Some(1), None]
+        );
+    }
+
+    #[test]
+    fn pmi_prefers_dependent_pairs() {
+        let words = [("ab", 4), ("ba", 4), ("aa", 4), ("bb", 4), ("xy", 3)]
+            .iter()
+            .flat_map(|&(w, n)| std::iter::repeat_n(w.chars().collect::<Vec<_>>(), n))
+            .collect::<Vec<_>>();
+
+        let mut vocab = Vocab::new(words.clone());
+        vocab.merge(2).unwrap();
+        assert_eq!(vocab.order, [vec!['a', 'a']]);
+
+        let mut vocab = Vocab::new(words);
+        vocab.merge_pmi(2).unwrap();
+        assert_eq!(vocab.order, [vec!['x', 'y']]);
+        assert_eq!(vocab.merge_records().next().unwrap().frequency(), 3);
+        assert!(vocab.merge_pmi(5).is_err());
+    }
+
+    #[test]
+    fn reset_allows_retraining() {
+        let words = data_words();
+        let mut fresh = Vocab::new(words.clone());
+        fresh.train(50, 3);
+
+        let mut vocab = Vocab::new(words);
+        vocab.train(100, 1);
+        vocab.reset();
+        assert!(vocab.tokens().is_empty());
+        vocab.train(50, 3);
+        assert_eq!(vocab.order, fresh.order);
+        for (a, b) in vocab.merge_records().zip(fresh.merge_records()) {
+            assert_eq!(
+                (a.left(), a.right(), a.frequency()),
+                (b.left(), b.right(), b.frequency())
+            );
+        }
+    }
+
+    #[test]
+    fn restrict_to_domain() {
+        let words = data_words();
+        let mut vocab = Vocab::new(words.clone()).with_seed_tokens(('a'..='z').map(|c| vec![c]));
+        vocab.train(200, 2);
+        let general = vocab.build();
+
+        let domain = &words[..20];
+        let restricted = general.restrict_to_corpus(domain);
+        assert!(restricted.vocab_size() < general.vocab_size());
+        for word in domain {
+            assert_eq!(restricted.tokenize(word), general.tokenize(word));
+        }
+        for c in 'a'..='z' {
+            assert!(restricted.token_to_id(&[c]).is_some());
+        }
+
+        let general = general.with_merge_ranks();
+        let restricted = general.restrict_to_corpus(domain);
+        assert!(restricted.vocab_size() < general.vocab_size());
+        for word in domain {
+            assert_eq!(restricted.tokenize(word), general.tokenize(word));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn training_emits_events() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        };
+        use tracing::{field, span, subscriber, Event, Metadata, Subscriber};
+
+        #[derive(Default)]
+        struct Counter {
+            merges: AtomicUsize,
+            events: AtomicUsize,
+            tokens: Mutex<Vec<String>>,
+            sizes: Mutex<Vec<u64>>,
+        }
+        struct Counting(Arc<Counter>);
+        impl Subscriber for Counting {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                self.0.events.fetch_add(1, Ordering::Relaxed);
+                if event.metadata().fields().field("split").is_none() {
+                    return;
+                }
+                self.0.merges.fetch_add(1, Ordering::Relaxed);
+                struct Fields<'a>(&'a Counter);
+                impl field::Visit for Fields<'_> {
+                    fn record_str(&mut self, field: &field::Field, value: &str) {
+                        if field.name() == "token" {
+                            self.0.tokens.lock().unwrap().push(value.to_string());
+                        }
+                    }
+                    fn record_u64(&mut self, field: &field::Field, value: u64) {
+                        if field.name() == "vocab_size" {
+                            self.0.sizes.lock().unwrap().push(value);
+                        }
+                    }
+                    fn record_debug(&mut self, _: &field::Field, _: &dyn fmt::Debug) {}
+                }
+                event.record(&mut Fields(&self.0));
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let counter = Arc::new(Counter::default());
+        let mut vocab = Vocab::new(["banana", "bandana"].map(|w| w.chars()));
+        subscriber::with_default(Counting(counter.clone()), || vocab.train(4, 1));
+        let merges = vocab.merge_records().count();
+        assert!(merges > 0);
+        assert_eq!(counter.merges.load(Ordering::Relaxed), merges);
+        assert_eq!(counter.events.load(Ordering::Relaxed), merges + 1);
+        assert!(counter.tokens.lock().unwrap().is_empty());
+
+        // Single merges are traced too, with the token once asked for.
+        let counter = Arc::new(Counter::default());
+        let mut vocab = Vocab::new(["banana", "bandana"].map(|w| w.chars())).trace_tokens();
+        subscriber::with_default(Counting(counter.clone()), || {
+            vocab.merge(1).unwrap();
+            vocab.merge_filtered(1, |_, _| true).unwrap();
+        });
+        assert_eq!(counter.merges.load(Ordering::Relaxed), 2);
+        let expected = vocab
+            .order
+            .iter()
+            .map(|x| format!("{x:?}"))
+            .collect::<Vec<_>>();
+        assert_eq!(*counter.tokens.lock().unwrap(), expected);
+
+        // Merging a token that is already known does not grow the
+        // vocabulary, and the event says so.
+        let counter = Arc::new(Counter::default());
+        let abc = "abc".chars().collect::<Vec<_>>();
+        let mut vocab = Vocab::new([abc.clone()]).with_seed_tokens([abc.clone()]);
+        let steps = subscriber::with_default(Counting(counter.clone()), || {
+            [vocab.apply_merge(vec!['a', 'b']), vocab.apply_merge(abc)]
+                .map(|step| step.unwrap().vocab_size as u64)
+        });
+        assert_eq!(steps, [2, 2]);
+        assert_eq!(*counter.sizes.lock().unwrap(), steps);
+    }
+
+    #[test]
+    fn atomic_units_stay_whole() {
+        let words = ["on2024x", "in2024x", "2024x", "x2024", "on20", "in24"].map(|w| w.chars());
+        let unit = "2024".chars().collect::<Vec<_>>();
+        let mut vocab = Vocab::with_atomic_units(words, vec![unit.clone()]);
+        vocab.train(20, 2);
+        assert!(vocab.tokens().contains(&unit));
+        assert!(vocab
+            .tokens()
+            .contains(&"2024x".chars().collect::<Vec<_>>()));
+
+        let tokenizer = vocab.build();
+        for word in ["on2024x", "in2024x", "x2024", "2024"] {
+            let word = word.chars().collect::<Vec<_>>();
+            let start = word.windows(4).position(|w| w == unit).unwrap();
+            let mut pos = 0;
+            for token in tokenizer.tokenize(&word) {
+                pos += token.len();
+                assert!(!(start + 1..start + 4).contains(&pos));
+            }
+        }
+    }
+
+    #[test]
+    fn base_alphabet() {
+        let vocab = Vocab::new(["banana", "bandana", ""].map(|w| w.chars()));
+        assert_eq!(
+            vocab.required_base_alphabet(),
+            HashSet::from(['a', 'b', 'd', 'n'])
+        );
+
+        let alphabet = vocab.required_base_alphabet().into_iter().map(|c| vec![c]);
+        let tokenizer = vocab.with_seed_tokens(alphabet).build_checked().unwrap();
+        let word = "dabnab".chars().collect::<Vec<_>>();
+        assert!(!tokenizer.encode(&word).contains(&tokenizer.unk_id()));
+    }
+
+    #[test]
+    fn encode_prefers_exact_surface_form() {
+        let tokenizer =
+            Tokenizer::from_tokens(["a", "A", "b", "ab"].map(|x| x.chars().collect::<Vec<_>>()))
+                .unwrap()
+                .with_normalizer(|c| c.to_ascii_lowercase());
+        let id = |x: &str| {
+            tokenizer
+                .token_to_id(&x.chars().collect::<Vec<_>>())
+                .unwrap()
+        };
+        let encode = |x: &str| tokenizer.encode(&x.chars().collect::<Vec<_>>());
+        assert_eq!(encode("A"), [id("A")]);
+        assert_eq!(encode("a"), [id("a")]);
+        assert_eq!(encode("B"), [id("b")]);
+        assert_eq!(encode("Ab"), [id("ab")]);
+        assert_eq!(encode("A B"), [id("A"), tokenizer.unk_id(), id("b")]);
+    }
+
+    #[test]
+    fn bigram_counts() {
+        let tokenizer =
+            Tokenizer::from_tokens(["ab", "c"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let words = ["abcab", "cab", "c", "abab"].map(|x| x.chars().collect::<Vec<_>>());
+        let counts = tokenizer.token_ngram_counts(&words, 2);
+        assert_eq!(
+            counts,
+            HashMap::from([(vec![1, 2], 1), (vec![2, 1], 2), (vec![1, 1], 1)])
+        );
+        assert_eq!(
+            tokenizer.token_ngram_counts(&words, 3),
+            HashMap::from([(vec![1, 2, 1], 1)])
+        );
+    }
+
+    #[test]
+    fn peek_predicts_merge() {
+        let mut vocab = Vocab::new(["banana", "bandana", "cabana"].map(|w| w.chars()));
+        while let Ok((token, frequency)) = vocab.peek_merge(2) {
+            assert_eq!(vocab.peek_merge(2), Ok((token.clone(), frequency)));
+            vocab.merge(2).unwrap();
+            let record = vocab.merge_records().last().unwrap();
+            assert_eq!(
+                (record.token(), record.frequency()),
+                (&token[..], frequency)
+            );
+        }
+        assert_eq!(vocab.peek_merge(2), Err(MergeError::NoCandidate(2)));
+        assert!(vocab.merge(2).is_err());
+    }
+
+    #[test]
+    fn guided_merges() {
+        let words = ["banana", "bandana", "cabana"].map(|w| w.chars().collect::<Vec<_>>());
+        let mut vocab = Vocab::new(words.clone());
+        let token = |x: &str| x.chars().collect::<Vec<_>>();
+        assert_eq!(vocab.apply_merge(token("ana")), Err(MergeError::NoSuchPair));
+        assert_eq!(
+            vocab.apply_merge(token("ab")).map(|x| x.token),
+            Ok(token("ab"))
+        );
+        assert_eq!(
+            vocab.apply_merge(token("cab")).map(|x| x.token),
+            Ok(token("cab"))
+        );
+        assert_eq!(vocab.apply_merge(token("xy")), Err(MergeError::NoSuchPair));
+        assert_eq!(
+            vocab
+                .merge_records()
+                .map(|r| r.frequency())
+                .collect::<Vec<_>>(),
+            [1, 1]
+        );
+
+        let tokenizer = vocab.build();
+        let tokens = tokenizer.tokenize(&words[2]);
+        assert_eq!(tokens[0], token("cab"));
+        assert_eq!(tokenizer.tokenize(&token("abcab"))[0], token("ab"));
+    }
+
+    #[test]
+    fn self_check_and_rebuild() {
+        let words = data_words();
+        let tokenizer = train(words.clone(), 150, 2);
+        tokenizer.self_check();
+
+        let sorted = tokenizer.tokens_sorted();
+        assert!(sorted.windows(2).all(|x| x[0] < x[1]));
+        let rebuilt = Tokenizer::from_tokens(sorted.iter().map(|x| x.to_vec())).unwrap();
+        rebuilt.self_check();
+        for word in &words {
+            assert_eq!(rebuilt.tokenize(word), tokenizer.tokenize(word));
+        }
+    }
+
+    #[test]
+    fn frequency_per_length_prefers_short_merges() {
+        // "abc" can be merged from "ab" + "c" 5 times, "de" occurs 4 times;
+        // per symbol that is 5 / 3 against 4 / 2.
+        let words = [("abc", 5), ("de", 4)]
+            .iter()
+            .flat_map(|&(w, n)| std::iter::repeat_n(w.chars().collect::<Vec<_>>(), n))
+            .collect::<Vec<_>>();
+        let token = |x: &str| x.chars().collect::<Vec<_>>();
+
+        let mut vocab = Vocab::new(words.clone());
+        vocab.apply_merge(token("ab")).unwrap();
+        vocab.merge_scored(2, MergeScore::Frequency).unwrap();
+        assert_eq!(vocab.order.last(), Some(&token("abc")));
+
+        let mut vocab = Vocab::new(words);
+        vocab.apply_merge(token("ab")).unwrap();
+        vocab
+            .merge_scored(2, MergeScore::FrequencyPerLength)
+            .unwrap();
+        assert_eq!(vocab.order.last(), Some(&token("de")));
+    }
+
+    #[test]
+    fn preallocated_corpus_trains_identically() {
+        let words = data_words();
+        let mut vocab = Vocab::with_capacity(words.len(), 8);
+        for word in &words {
+            vocab.push_word(word.iter().copied());
+        }
+        vocab.push_word([]);
+        assert!(vocab.words.capacity() >= words.len());
+
+        let mut fresh = Vocab::new(words);
+        assert_eq!(vocab.words.len(), fresh.words.len());
+        vocab.train(100, 2);
+        fresh.train(100, 2);
+        assert_eq!(vocab.order, fresh.order);
+    }
+
+    #[test]
+    fn pieces_with_ids() {
+        let words = data_words();
+        let tokenizer = train(words.clone(), 100, 2);
+        let fallback = train(words.clone(), 100, 2).with_byte_fallback();
+        for tokenizer in [&tokenizer, &fallback] {
+            for word in words.iter().chain([&"né🍌".chars().collect()]) {
+                let pieces = tokenizer.encode_with_pieces(word);
+                let ids = pieces.iter().map(|x| x.0).collect::<Vec<_>>();
+                assert_eq!(ids, tokenizer.encode(word));
+                assert_eq!(
+                    pieces.into_iter().flat_map(|x| x.1).collect::<Vec<_>>(),
+                    *word
+                );
+            }
         }
     }
+
+    #[test]
+    fn encode_checked_limits_input() {
+        let tokenizer =
+            Tokenizer::from_tokens(["ab", "c"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let word = "abcab".chars().collect::<Vec<_>>();
+        assert_eq!(
+            tokenizer.encode_checked(&word, 5),
+            Ok(tokenizer.encode(&word))
+        );
+        assert_eq!(
+            tokenizer.encode_checked(&word, 4),
+            Err(EncodeError::InputTooLong { len: 5, max: 4 })
+        );
+    }
+
+    #[test]
+    fn repair_lossless() {
+        let mut vocab = Vocab::new(["banana", "bandana"].map(|w| w.chars()));
+        let alphabet = vocab.required_base_alphabet().into_iter().map(|c| vec![c]);
+        vocab = vocab.with_seed_tokens(alphabet);
+        vocab.train(12, 2);
+        let mut tokenizer = vocab.build().with_normalizer(|c| c.to_ascii_lowercase());
+
+        let corpus =
+            ["banana", "Banana", "BANDANA", "bandanas"].map(|w| w.chars().collect::<Vec<_>>());
+        assert!(tokenizer.is_lossless(&corpus[..1]));
+        assert!(!tokenizer.is_lossless(&corpus));
+        let before = corpus
+            .iter()
+            .map(|w| tokenizer.tokenize_owned(w.clone()))
+            .collect::<Vec<_>>();
+        let size = tokenizer.vocab_size();
+
+        tokenizer.ensure_lossless(&corpus);
+        assert!(tokenizer.is_lossless(&corpus));
+        assert!(tokenizer.vocab_size() > size);
+        let after = corpus
+            .iter()
+            .map(|w| tokenizer.tokenize_owned(w.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn balanced_corpora_learn_domain_tokens() {
+        let corpus = |words: &[(&str, usize)]| {
+            words
+                .iter()
+                .flat_map(|&(w, n)| std::iter::repeat_n(w.chars().collect::<Vec<_>>(), n))
+                .collect::<Vec<_>>()
+        };
+        let general = corpus(&[("ab", 10), ("cd", 8), ("ef", 6)]);
+        let domain = corpus(&[("xy", 2)]);
+        let token = vec!['x', 'y'];
+
+        let mut vocab =
+            Vocab::from_balanced_corpora(vec![(general.clone(), 1.0), (domain.clone(), 1.0)]);
+        vocab.train(2, 1);
+        assert!(!vocab.tokens().contains(&token));
+
+        let mut vocab = Vocab::from_balanced_corpora(vec![(general, 1.0), (domain, 10.0)]);
+        vocab.train(2, 1);
+        assert_eq!(vocab.order[0], token);
+        assert_eq!(vocab.merge_records().next().unwrap().frequency(), 20);
+
+        // A small weight scales every count up rather than rounding the
+        // rare words away.
+        let vocab = Vocab::from_balanced_corpora(vec![
+            (corpus(&[("ab", 10)]), 1.0),
+            (corpus(&[("xy", 1), ("zw", 3)]), 0.1),
+        ]);
+        assert_eq!(vocab.weights, [100, 1, 3]);
+    }
+
+    #[test]
+    fn enumerate_segmentations() {
+        let tokenizer =
+            Tokenizer::from_tokens(["ab", "bc"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let word = "abc".chars().collect::<Vec<_>>();
+        let segmentations = tokenizer
+            .all_segmentations(&word, 10)
+            .into_iter()
+            .map(|x| x.into_iter().map(|t| t.iter().collect()).collect())
+            .collect::<Vec<Vec<String>>>();
+        assert_eq!(
+            segmentations,
+            [vec!["ab", "c"], vec!["a", "bc"], vec!["a", "b", "c"]]
+        );
+        assert_eq!(
+            tokenizer.all_segmentations(&word, 1),
+            [tokenizer.tokenize(&word)]
+        );
+        assert_eq!(tokenizer.all_segmentations(&word, 2).len(), 2);
+
+        let mut tokenizer = tokenizer;
+        tokenizer.with_protected(vec![vec!['b', 'c']]);
+        assert_eq!(tokenizer.all_segmentations(&word, 10).len(), 1);
+    }
+
+    #[test]
+    fn reserved_special_ids() {
+        let words = ["banana", "bandana"].map(|w| w.chars().collect::<Vec<_>>());
+        let plain = train(words.clone(), 10, 1);
+        let tokenizer = plain.with_special_tokens(["<pad>", "<bos>", "<eos>", "<unk>"]);
+        assert_eq!(tokenizer.vocab_size(), plain.vocab_size() + 3);
+        assert_eq!(tokenizer.special_id("<unk>"), Some(tokenizer.unk_id()));
+        assert_eq!(tokenizer.special_id("<pad>"), Some(1));
+        assert_eq!(tokenizer.special_id("<eos>"), Some(3));
+        assert_eq!(tokenizer.special_id("<mask>"), None);
+
+        let word = "bandanas".chars().collect::<Vec<_>>();
+        let ids = tokenizer.encode(&word);
+        assert_eq!(
+            ids,
+            plain
+                .encode(&word)
+                .into_iter()
+                .map(|id| if id == plain.unk_id() { id } else { id + 3 })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(ids.last(), Some(&tokenizer.unk_id()));
+
+        let mut framed = vec![1, 2];
+        framed.extend(&ids);
+        framed.push(3);
+        assert_eq!(tokenizer.decode(&framed), tokenizer.decode(&ids));
+        for id in 4..tokenizer.vocab_size() {
+            assert_eq!(tokenizer.id_to_token(id), plain.id_to_token(id - 3));
+        }
+
+        let narrow = tokenizer.encode_u32(&word);
+        assert!(narrow.iter().map(|&id| id as usize).eq(ids.iter().copied()));
+        assert_eq!(tokenizer.decode_u32(&narrow), tokenizer.decode(&ids));
+        assert_eq!(
+            tokenizer.decode_u32(&[u32::MAX]),
+            Err(DecodeError::InvalidId(u32::MAX as usize))
+        );
+    }
+
+    #[test]
+    fn merge_ranks_replay_training() {
+        let words = [("bc", 3), ("ab", 2)]
+            .iter()
+            .flat_map(|&(w, n)| std::iter::repeat_n(w.chars().collect::<Vec<_>>(), n));
+        let mut vocab = Vocab::new(words);
+        vocab.train(2, 1);
+        let alphabet = vocab.required_base_alphabet().into_iter().map(|c| vec![c]);
+        vocab = vocab.with_seed_tokens(alphabet);
+        let greedy = vocab.build();
+        let ranked = vocab.build().with_merge_ranks();
+        let word = "abc".chars().collect::<Vec<_>>();
+        assert_eq!(greedy.tokenize(&word), [&['a', 'b'][..], &['c']]);
+        assert_eq!(ranked.tokenize(&word), [&['a'][..], &['b', 'c']]);
+        assert_eq!(ranked.decode(&ranked.encode(&word)).unwrap(), word);
+
+        let words = data_words()
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        let mut vocab = Vocab::new(words.clone());
+        vocab.train(100, 2);
+        let ranked = vocab.build().with_merge_ranks();
+        for (word, trained) in words.iter().zip(&vocab.words) {
+            let mut expected = Vec::new();
+            let mut pos = 0;
+            while pos < trained.len() {
+                expected.push(trained[pos].token_head.get());
+                pos += trained[pos].token_head.get();
+            }
+            let lengths = ranked
+                .tokenize(word)
+                .iter()
+                .map(|x| x.len())
+                .collect::<Vec<_>>();
+            assert_eq!(lengths, expected, "{}", word.iter().collect::<String>());
+        }
+    }
+
+    #[test]
+    fn incremental_train_matches_merge() {
+        let words = data_words();
+        let mut trained = Vocab::new(words.clone());
+        trained.train(100, 2);
+        let mut merged = Vocab::new(words.clone());
+        while merged.tokens.len() < 100 && merged.merge_filtered(2, |_, _| true).is_ok() {}
+
+        let records = |vocab: &Vocab<char>| {
+            vocab
+                .merge_records()
+                .map(|m| (m.left().to_vec(), m.right().to_vec(), m.frequency()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(trained.order, merged.order);
+        assert_eq!(records(&trained), records(&merged));
+        for word in &words {
+            assert_eq!(
+                trained.build().tokenize(word),
+                merged.build().tokenize(word)
+            );
+        }
+
+        // Merging continues from the state `train` left behind, and the
+        // kept counts follow merges made another way.
+        trained.merge(2).unwrap();
+        merged.merge_filtered(2, |_, _| true).unwrap();
+        trained
+            .merge_scored(2, MergeScore::FrequencyPerLength)
+            .unwrap();
+        merged
+            .merge_scored(2, MergeScore::FrequencyPerLength)
+            .unwrap();
+        trained.merge(2).unwrap();
+        merged.merge_filtered(2, |_, _| true).unwrap();
+        assert_eq!(trained.order, merged.order);
+    }
+
+    #[test]
+    fn counts_train_like_repeated_words() {
+        let counts = [("banana", 3u64), ("bandana", 2), ("cabana", 1), ("ana", 0)];
+        let mut counted = Vocab::from_counts(counts.map(|(w, n)| (w.chars(), n)));
+        counted.train(10, 2);
+        let mut repeated = Vocab::new(
+            counts
+                .iter()
+                .flat_map(|&(w, n)| std::iter::repeat_n(w.chars(), n as usize)),
+        );
+        repeated.train(10, 2);
+        assert_eq!(counted.words.len(), 3);
+        assert_eq!(counted.order, repeated.order);
+        assert_eq!(
+            counted.merge_records().next().unwrap().frequency(),
+            repeated.merge_records().next().unwrap().frequency()
+        );
+    }
+
+    #[test]
+    fn merge_steps_report_progress() {
+        let words = data_words();
+
+        let mut vocab = Vocab::new(words.clone());
+        let step = vocab.merge(2).unwrap();
+        let record = vocab.merge_records().next().unwrap();
+        assert_eq!(step.token, record.token());
+        assert_eq!(step.split, record.left().len());
+        assert_eq!(step.frequency, record.frequency());
+        assert_eq!(step.vocab_size, 1);
+
+        // Stop once merges get rare, which `train` cannot express. The
+        // merge `take_while` rejects has already been applied.
+        let steps = vocab
+            .merge_iter(2)
+            .take_while(|x| x.frequency >= 5)
+            .collect::<Vec<_>>();
+        assert!(steps
+            .windows(2)
+            .all(|x| x[1].vocab_size == x[0].vocab_size + 1));
+        assert!(steps.iter().all(|x| x.frequency >= 5));
+        assert_eq!(vocab.merges.len(), steps.len() + 2);
+        assert!(vocab.merge_records().last().unwrap().frequency() < 5);
+
+        let mut progress = Vec::new();
+        let mut trained = Vocab::new(words);
+        trained.train_with_progress(30, 2, |x| progress.push(x.clone()));
+        assert_eq!(progress.len(), 30);
+        assert_eq!(progress.last().unwrap().vocab_size, 30);
+        assert!(progress
+            .iter()
+            .zip(trained.merge_records())
+            .all(|(x, r)| x.token == r.token() && x.frequency == r.frequency()));
+        assert_eq!(
+            trained.merge(usize::MAX),
+            Err(MergeError::NoCandidate(usize::MAX))
+        );
+    }
 }
@@ -0,0 +1,105 @@
+use std::{
+    hash::Hash,
+    io::{self, BufRead, Write},
+};
+
+use crate::Tokenizer;
+
+/// One token per line, sorted. Only the tokens are written: special tokens,
+/// including any added with `with_special_tokens`, and ids are not, so the
+/// file does not keep the ids of a tokenizer.
+impl<C: Ord + Hash + Clone> Tokenizer<C> {
+    /// Writes the tokens in sorted order. Fails with `InvalidInput` if
+    /// `render` returns a line break, which would split a token over two
+    /// lines; such tokens need escaping.
+    pub fn write_lines<W: Write>(
+        &self,
+        mut w: W,
+        render: impl Fn(&[C]) -> String,
+    ) -> io::Result<()> {
+        let mut tokens = self.tokens.iter().flatten().collect::<Vec<_>>();
+        tokens.sort();
+        for token in tokens {
+            let line = render(token);
+            if line.contains(['\n', '\r']) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "rendered token contains a line break",
+                ));
+            }
+            writeln!(w, "{line}")?;
+        }
+        Ok(())
+    }
+
+    /// Reads one token per line. Ids are assigned afresh: `<unk>` first,
+    /// then the tokens in line order, as `from_tokens` does.
+    pub fn read_lines<R: BufRead>(r: R, parse: impl Fn(&str) -> Vec<C>) -> io::Result<Self> {
+        let tokens = r
+            .lines()
+            .map(|line| line.map(|x| parse(&x)))
+            .collect::<io::Result<Vec<_>>>()?;
+        Self::from_tokens(tokens)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "empty token"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::train;
+
+    use super::*;
+
+    #[test]
+    fn lines_round_trip() {
+        let words =
+            ["banana", "bandana", "ananas", "cabana"].map(|w| w.chars().collect::<Vec<_>>());
+        let tokenizer = train(words.clone(), 10, 1);
+
+        let mut buf = Vec::new();
+        tokenizer
+            .write_lines(&mut buf, |x| x.iter().collect())
+            .unwrap();
+        let text = String::from_utf8(buf.clone()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), tokenizer.vocab_size() - 1);
+        assert!(lines.windows(2).all(|x| x[0] < x[1]));
+
+        let loaded = Tokenizer::read_lines(buf.as_slice(), |x| x.chars().collect()).unwrap();
+        assert_eq!(loaded.vocab_size(), tokenizer.vocab_size());
+        for (id, line) in lines.iter().enumerate() {
+            let token = line.chars().collect::<Vec<_>>();
+            assert_eq!(loaded.id_to_token(id + 1), Some(&token[..]));
+            assert!(tokenizer.token_to_id(&token).is_some());
+        }
+        for word in &words {
+            assert_eq!(loaded.tokenize(word), tokenizer.tokenize(word));
+        }
+
+        // Special tokens are not written.
+        let special = tokenizer.with_special_tokens(["<pad>"]);
+        let mut buf = Vec::new();
+        special
+            .write_lines(&mut buf, |x| x.iter().collect())
+            .unwrap();
+        assert_eq!(buf, text.as_bytes());
+    }
+
+    #[test]
+    fn lines_reject_line_breaks() {
+        let tokenizer =
+            Tokenizer::from_tokens(["a\nb", "ab"].map(|x| x.chars().collect::<Vec<_>>())).unwrap();
+        let err = tokenizer
+            .write_lines(Vec::new(), |x| x.iter().collect())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut buf = Vec::new();
+        tokenizer
+            .write_lines(&mut buf, |x| {
+                x.iter().collect::<String>().replace('\n', "\\n")
+            })
+            .unwrap();
+        assert_eq!(buf, b"a\\nb\nab\n");
+    }
+}
@@ -0,0 +1,95 @@
+use std::hash::Hash;
+
+use rayon::prelude::*;
+
+use crate::Tokenizer;
+
+impl<C: Ord + Hash + Clone + Send + Sync> Tokenizer<C> {
+    /// Same result as `tokenize`, computed in parallel over chunks of
+    /// `chunk_size` symbols.
+    ///
+    /// Each chunk is tokenized from its first symbol, letting its last token
+    /// run up to `max_token_len() - 1` symbols into the next chunk. When
+    /// stitching, the sequential position reached at a chunk start may not
+    /// be one of the chunk's token boundaries; tokens are then recomputed
+    /// one at a time until the position lands on a boundary of the chunk,
+    /// after which greedy matching is guaranteed to agree with the rest of
+    /// the chunk's tokens. Tokenizers with protected tokens or merge ranks
+    /// fall back to `tokenize`.
+    pub fn tokenize_chunked_parallel<'a>(&self, word: &'a [C], chunk_size: usize) -> Vec<&'a [C]> {
+        if chunk_size == 0
+            || word.len() <= chunk_size
+            || !self.protected.is_empty()
+            || self.merge_ranked
+        {
+            return self.tokenize(word);
+        }
+
+        let normalized = self.normalize(word);
+        let chunks = (0..word.len())
+            .step_by(chunk_size)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|start| {
+                let end = (start + chunk_size).min(word.len());
+                let mut tokens = Vec::new();
+                let mut pos = start;
+                while pos < end {
+                    let n = self.longest_prefix(&normalized[pos..]);
+                    tokens.push((pos, n));
+                    pos += n;
+                }
+                tokens
+            })
+            .collect::<Vec<_>>();
+
+        let mut result = Vec::new();
+        let mut pos = 0;
+        for tokens in &chunks {
+            let end = tokens.last().map_or(pos, |&(start, n)| start + n);
+            while pos < end {
+                if let Ok(k) = tokens.binary_search_by_key(&pos, |&(start, _)| start) {
+                    result.extend(
+                        tokens[k..]
+                            .iter()
+                            .map(|&(start, n)| &word[start..start + n]),
+                    );
+                    pos = end;
+                    break;
+                }
+                let n = self.longest_prefix(&normalized[pos..]);
+                result.push(&word[pos..pos + n]);
+                pos += n;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::data_words, train};
+
+    #[test]
+    fn chunked_parallel_matches_sequential() {
+        let words = data_words();
+        let tokenizer = train(words.clone(), 100, 2);
+        assert!(tokenizer.max_token_len() > 2);
+
+        let document = words.join(&' ');
+        let sequential = tokenizer.tokenize(&document);
+        for chunk_size in [1, 2, 3, 7, 64, 1000, document.len()] {
+            assert_eq!(
+                tokenizer.tokenize_chunked_parallel(&document, chunk_size),
+                sequential
+            );
+        }
+
+        let tokenizer = tokenizer.with_merge_ranks();
+        let document = words[..50].join(&' ');
+        assert_eq!(
+            tokenizer.tokenize_chunked_parallel(&document, 7),
+            tokenizer.tokenize(&document)
+        );
+    }
+}
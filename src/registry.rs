@@ -0,0 +1,149 @@
+use std::{collections::HashMap, hash::Hash};
+
+use crate::{MergeError, MergeStep, Tokenizer, Vocab};
+
+/// Append-only id assignment shared across training runs, so tokens keep
+/// their ids when the vocabulary grows. Ids are laid out like a
+/// `Tokenizer`'s: `<unk>` first, then tokens in registration order. With
+/// `C: BinSymbol` it can be stored with `save` and read back with `load`.
+pub struct IdRegistry<C> {
+    pub(crate) specials: Vec<String>,
+    pub(crate) tokens: Vec<Vec<C>>,
+    pub(crate) ids: HashMap<Vec<C>, usize>,
+}
+
+impl<C: Ord + Hash + Clone> IdRegistry<C> {
+    pub fn new() -> Self {
+        Self {
+            specials: vec!["<unk>".to_string()],
+            tokens: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Returns the id of `token`, assigning the next free one if it is new.
+    pub fn register(&mut self, token: Vec<C>) -> usize {
+        if let Some(&id) = self.ids.get(&token) {
+            return id;
+        }
+        let id = self.specials.len() + self.tokens.len();
+        self.ids.insert(token.clone(), id);
+        self.tokens.push(token);
+        id
+    }
+
+    /// Registers the tokens of `vocab` in the order it learned them.
+    pub fn register_vocab(&mut self, vocab: &Vocab<C>) {
+        for token in &vocab.order {
+            self.register(token.clone());
+        }
+    }
+
+    pub fn id(&self, token: &[C]) -> Option<usize> {
+        self.ids.get(token).copied()
+    }
+
+    /// Number of tokens registered, not counting special tokens.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl<C: Ord + Hash + Clone> Default for IdRegistry<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Ord + Hash + Clone> Vocab<C> {
+    /// Like `merge`, also registering the new token and returning its id.
+    pub fn merge_registered(
+        &mut self,
+        min_freq: usize,
+        registry: &mut IdRegistry<C>,
+    ) -> Result<(MergeStep<C>, usize), MergeError> {
+        let step = self.merge(min_freq)?;
+        let id = registry.register(step.token.clone());
+        Ok((step, id))
+    }
+
+    /// Like `train`, registering each token as soon as it is merged.
+    pub fn train_registered(
+        &mut self,
+        vocab_size: usize,
+        min_freq: usize,
+        registry: &mut IdRegistry<C>,
+    ) {
+        self.train_with_progress(vocab_size, min_freq, |step| {
+            registry.register(step.token.clone());
+        })
+    }
+}
+
+impl<C: Ord + Hash + Clone> Tokenizer<C> {
+    /// A tokenizer with every token of `registry` under its registered id.
+    pub fn from_registry(registry: &IdRegistry<C>) -> Self {
+        Self::from_parts(
+            registry.specials.clone(),
+            0,
+            registry.tokens.iter().cloned().map(Some).collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_stay_stable_across_phases() {
+        let words = |text: &str| {
+            text.split(' ')
+                .map(|w| w.chars().collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        let mut registry = IdRegistry::new();
+
+        let mut vocab = Vocab::new(words("banana bandana cabana banana"));
+        vocab.train(6, 2);
+        registry.register_vocab(&vocab);
+        let first = Tokenizer::from_registry(&registry);
+
+        let mut vocab = Vocab::new(words("tomato potato tomato motto banana"));
+        vocab.train(6, 2);
+        registry.register_vocab(&vocab);
+        let second = Tokenizer::from_registry(&registry);
+
+        assert!(second.vocab_size() > first.vocab_size());
+        for id in 0..first.vocab_size() {
+            assert_eq!(first.id_to_token(id), second.id_to_token(id));
+        }
+        let token = second.id_to_token(first.vocab_size()).unwrap().to_vec();
+        assert_eq!(registry.register(token), first.vocab_size());
+        assert_eq!(registry.len() + 1, second.vocab_size());
+    }
+
+    #[test]
+    fn merges_register_immediately() {
+        let mut registry = IdRegistry::new();
+        assert!(registry.is_empty());
+        assert_eq!(registry.len(), 0);
+
+        let words = ["banana", "bandana", "cabana"].map(|w| w.chars().collect::<Vec<_>>());
+        let mut vocab = Vocab::new(words.clone());
+        let (step, id) = vocab.merge_registered(2, &mut registry).unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(registry.id(&step.token), Some(1));
+        assert!(!registry.is_empty());
+
+        vocab.train_registered(5, 2, &mut registry);
+        assert_eq!(registry.len(), vocab.order.len());
+        for token in &vocab.order {
+            assert!(registry.id(token).is_some());
+        }
+    }
+}
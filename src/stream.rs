@@ -0,0 +1,203 @@
+//! Byte stream filters for wrapping a `Tokenizer<u8>` in a shell pipeline,
+//! and incremental tokenization of input that arrives in chunks. Ids are
+//! written as unsigned LEB128 varints.
+
+use std::{
+    hash::Hash,
+    io::{self, BufRead, Read, Write},
+};
+
+use crate::{PrefixMatcher, Tokenizer};
+
+/// Tokenizes `r` line by line, keeping the newlines as input, and writes the
+/// ids to `w`.
+pub fn encode_stream<R: BufRead, W: Write>(
+    tok: &Tokenizer<u8>,
+    mut r: R,
+    mut w: W,
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    while r.read_until(b'\n', &mut line)? > 0 {
+        for id in tok.encode(&line) {
+            write_varint(&mut w, id)?;
+        }
+        line.clear();
+    }
+    w.flush()
+}
+
+/// Reads ids written by `encode_stream` and writes the decoded bytes.
+pub fn decode_stream<R: BufRead, W: Write>(
+    tok: &Tokenizer<u8>,
+    mut r: R,
+    mut w: W,
+) -> io::Result<()> {
+    while let Some(id) = read_varint(&mut r)? {
+        let bytes = tok
+            .decode(&[id])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        w.write_all(&bytes)?;
+    }
+    w.flush()
+}
+
+/// Tokenizes input fed in arbitrary chunks, giving the same tokens as
+/// tokenizing the concatenation at once. Symbols are buffered until the
+/// token starting at them can no longer change, which is once
+/// `max_token_len` symbols are known from there. With protected tokens or
+/// merge ranks everything is buffered until `finish`.
+pub struct WordStreamer<'a, C: Ord + Hash + Clone, M> {
+    tokenizer: &'a Tokenizer<C, M>,
+    buffer: Vec<C>,
+}
+
+impl<'a, C: Ord + Hash + Clone, M: PrefixMatcher<C>> WordStreamer<'a, C, M> {
+    pub fn new(tokenizer: &'a Tokenizer<C, M>) -> Self {
+        Self {
+            tokenizer,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Appends `chunk` and returns the tokens that are now complete.
+    pub fn push(&mut self, chunk: &[C]) -> Vec<Vec<C>> {
+        self.buffer.extend_from_slice(chunk);
+        if !self.tokenizer.protected.is_empty() || self.tokenizer.merge_ranked {
+            return Vec::new();
+        }
+
+        let max = self.tokenizer.max_token_len();
+        let mut done = 0;
+        let mut result = Vec::new();
+        for token in self.tokenizer.tokenize(&self.buffer) {
+            if done + max > self.buffer.len() {
+                break;
+            }
+            result.push(token.to_vec());
+            done += token.len();
+        }
+        self.buffer.drain(..done);
+        result
+    }
+
+    /// Symbols received but not yet returned as tokens.
+    pub fn pending(&self) -> &[C] {
+        &self.buffer
+    }
+
+    /// Tokenizes whatever is still buffered.
+    pub fn finish(self) -> Vec<Vec<C>> {
+        self.tokenizer.tokenize_owned(self.buffer)
+    }
+}
+
+fn write_varint<W: Write>(w: &mut W, mut n: usize) -> io::Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return w.write_all(&[byte]);
+        }
+        w.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Returns `None` at a clean end of input.
+fn read_varint<R: Read>(r: &mut R) -> io::Result<Option<usize>> {
+    let mut n = 0usize;
+    let mut shift = 0;
+    loop {
+        let mut buf = [0];
+        if r.read(&mut buf)? == 0 {
+            return match shift {
+                0 => Ok(None),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        // The last byte that fits may only carry the bits left in a usize.
+        let payload = (buf[0] & 0x7f) as usize;
+        if shift >= usize::BITS || payload >> (usize::BITS - shift).min(7) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "varint too long",
+            ));
+        }
+        n |= payload << shift;
+        if buf[0] & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::data_words, Vocab};
+
+    use super::*;
+
+    #[test]
+    fn stream_round_trip() {
+        let text = b"banana bandana\nananas cabana\n\nbanana";
+        let mut vocab = Vocab::new(text.split(|&b| b == b' ' || b == b'\n').map(|w| w.to_vec()));
+        vocab.train(10, 2);
+        let tok = vocab.with_seed_tokens((0..=255u8).map(|b| vec![b])).build();
+
+        let mut encoded = Vec::new();
+        encode_stream(&tok, &text[..], &mut encoded).unwrap();
+        let mut decoded = Vec::new();
+        decode_stream(&tok, encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        let past_end = tok.vocab_size();
+        let mut bad = Vec::new();
+        write_varint(&mut bad, past_end).unwrap();
+        assert!(decode_stream(&tok, bad.as_slice(), &mut Vec::new()).is_err());
+        assert!(decode_stream(&tok, &[0x80][..], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn overlong_varints_are_rejected() {
+        let mut max = Vec::new();
+        write_varint(&mut max, usize::MAX).unwrap();
+        assert_eq!(read_varint(&mut max.as_slice()).unwrap(), Some(usize::MAX));
+
+        // Bits past the top of a usize in the last byte, or a byte after it.
+        let last = max.len() - 1;
+        for byte in [max[last] | 0x02, max[last] | 0x80] {
+            let mut bad = max.clone();
+            bad[last] = byte;
+            bad.push(0);
+            let err = read_varint(&mut bad.as_slice()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn chunked_input_matches_whole() {
+        let tokenizer = Tokenizer::from_tokens(
+            ["ab", "abcd", "cd", "bc"].map(|x| x.chars().collect::<Vec<_>>()),
+        )
+        .unwrap();
+        let mut streamer = WordStreamer::new(&tokenizer);
+        let mut tokens = streamer.push(&['a', 'b']);
+        assert!(tokens.is_empty());
+        tokens.extend(streamer.push(&['c', 'd']));
+        tokens.extend(streamer.finish());
+        assert_eq!(tokens, [vec!['a', 'b', 'c', 'd']]);
+
+        let text = std::fs::read_to_string("data.txt").unwrap();
+        let tokenizer = crate::train(data_words(), 100, 2);
+        let text = text.chars().collect::<Vec<_>>();
+        for size in [1, 3, 7, 50] {
+            let mut streamer = WordStreamer::new(&tokenizer);
+            let mut tokens = Vec::new();
+            for chunk in text.chunks(size) {
+                tokens.extend(streamer.push(chunk));
+                assert!(streamer.pending().len() <= tokenizer.max_token_len() + size);
+            }
+            tokens.extend(streamer.finish());
+            assert_eq!(tokens, tokenizer.tokenize_owned(text.clone()));
+        }
+    }
+}
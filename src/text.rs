@@ -0,0 +1,238 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{self, BufRead},
+};
+
+use crate::{MergeError, MergeStep, Tokenizer, Vocab};
+
+/// Cleanup applied to raw file contents before training, so encoding
+/// artifacts do not end up in the vocabulary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextOptions {
+    /// Removes a leading U+FEFF byte order mark.
+    pub strip_bom: bool,
+    /// Rewrites `\r\n` and lone `\r` as `\n`.
+    pub normalize_newlines: bool,
+}
+
+impl TextOptions {
+    /// Enables every cleanup step.
+    pub fn clean() -> Self {
+        Self {
+            strip_bom: true,
+            normalize_newlines: true,
+        }
+    }
+
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if self.strip_bom {
+            if let Some(rest) = text.strip_prefix('\u{feff}') {
+                text = Cow::Owned(rest.to_string());
+            }
+        }
+        if self.normalize_newlines && text.contains('\r') {
+            text = Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"));
+        }
+        text
+    }
+}
+
+/// Simple lowercasing with `char::to_lowercase`. Characters whose lowercase
+/// form is more than one character, such as U+0130 'İ', are kept unchanged
+/// so the mapping stays one to one for `Tokenizer::with_normalizer`.
+pub fn lowercase(c: &char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) => l,
+        _ => *c,
+    }
+}
+
+impl Vocab<char> {
+    /// Like `new`, with every character mapped by `lowercase`.
+    pub fn new_lowercased<Words, Word>(words: Words) -> Self
+    where
+        Words: IntoIterator<Item = Word>,
+        Word: IntoIterator<Item = char>,
+    {
+        Self::new(
+            words
+                .into_iter()
+                .map(|w| w.into_iter().map(|c| lowercase(&c))),
+        )
+    }
+
+    /// Reads all of `r` and splits it into words at any of `delimiters`,
+    /// dropping empty words. Repeated words are stored once with their
+    /// count, which trains the same as listing every occurrence.
+    pub fn from_reader_split<R: BufRead>(mut r: R, delimiters: &[char]) -> io::Result<Self> {
+        let mut text = String::new();
+        r.read_to_string(&mut text)?;
+
+        let mut counts = Vec::<(&str, usize)>::new();
+        let mut index = HashMap::new();
+        for word in text.split(delimiters).filter(|w| !w.is_empty()) {
+            let i = *index.entry(word).or_insert_with(|| {
+                counts.push((word, 0));
+                counts.len() - 1
+            });
+            counts[i].1 += 1;
+        }
+        Ok(Self::from_weighted(
+            counts.into_iter().map(|(w, n)| (w.chars(), n)),
+        ))
+    }
+}
+
+impl Tokenizer<char> {
+    /// Matches input through `lowercase`, for tokenizers trained with
+    /// `Vocab::new_lowercased`.
+    pub fn with_lowercase(self) -> Self {
+        self.with_normalizer(lowercase)
+    }
+}
+
+/// A character-level vocabulary built from text, one word per line.
+pub struct TextVocab {
+    vocab: Vocab<char>,
+}
+
+impl TextVocab {
+    pub fn from_text(text: &str, options: TextOptions) -> Self {
+        let text = options.apply(text);
+        Self {
+            vocab: Vocab::new(text.split('\n').map(|w| w.chars())),
+        }
+    }
+
+    /// Like `from_text`, but uses extended grapheme clusters as the base
+    /// units, so merges never cut a user-perceived character in half.
+    #[cfg(feature = "unicode-segmentation")]
+    pub fn new_by_grapheme(text: &str, options: TextOptions) -> Self {
+        use unicode_segmentation::UnicodeSegmentation;
+
+        let text = options.apply(text);
+        Self {
+            vocab: Vocab::from_units(
+                text.split('\n')
+                    .map(|w| w.graphemes(true).map(|g| g.chars().collect())),
+            ),
+        }
+    }
+
+    pub fn merge(&mut self, min_freq: usize) -> Result<MergeStep<char>, MergeError> {
+        self.vocab.merge(min_freq)
+    }
+
+    pub fn build(&self) -> Tokenizer<char> {
+        self.vocab.build()
+    }
+
+    pub fn vocab(&self) -> &Vocab<char> {
+        &self.vocab
+    }
+
+    pub fn vocab_mut(&mut self) -> &mut Vocab<char> {
+        &mut self.vocab
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::byte_level::ByteVocab;
+
+    use super::*;
+
+    #[test]
+    fn bom_and_crlf_are_cleaned() {
+        let clean = "banana\nbandana\nananas\ncabana\n";
+        let dirty = "\u{feff}banana\r\nbandana\r\nananas\rcabana\r\n";
+
+        let mut a = TextVocab::from_text(clean, TextOptions::default());
+        let mut b = TextVocab::from_text(dirty, TextOptions::clean());
+        a.vocab_mut().train(10, 1);
+        b.vocab_mut().train(10, 1);
+        assert_eq!(a.vocab().tokens(), b.vocab().tokens());
+
+        let mut raw = TextVocab::from_text(dirty, TextOptions::default());
+        raw.vocab_mut().train(10, 1);
+        assert!(raw.vocab().tokens().iter().any(|x| x.contains(&'\r')));
+
+        let mut a = ByteVocab::from_text(clean, TextOptions::default());
+        let mut b = ByteVocab::from_text(dirty, TextOptions::clean());
+        while a.merge(1).is_ok() {}
+        while b.merge(1).is_ok() {}
+        assert_eq!(a.vocab().tokens(), b.vocab().tokens());
+    }
+
+    #[cfg(feature = "unicode-segmentation")]
+    #[test]
+    fn graphemes_are_not_split() {
+        use unicode_segmentation::UnicodeSegmentation;
+
+        let text = "🇯🇵🇯🇵\n🇯🇵🇫🇷\ne\u{301}e\u{301}te\u{301}\ncafe\u{301}\nte\u{301}te\u{301}";
+        let mut vocab = TextVocab::new_by_grapheme(text, TextOptions::default());
+        vocab.vocab_mut().train(30, 1);
+        let tokenizer = vocab.build();
+        assert!(tokenizer.token_to_id(&['🇯', '🇵']).is_some());
+        assert!(tokenizer.token_to_id(&['e', '\u{301}']).is_some());
+
+        for line in text.split('\n') {
+            let mut boundaries = vec![0];
+            for g in line.graphemes(true) {
+                boundaries.push(boundaries.last().unwrap() + g.chars().count());
+            }
+            let word = line.chars().collect::<Vec<_>>();
+            let mut pos = 0;
+            for token in tokenizer.tokenize(&word) {
+                pos += token.len();
+                assert!(
+                    boundaries.contains(&pos),
+                    "{line:?} split inside a grapheme"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lowercased_training() {
+        let mut vocab =
+            Vocab::new_lowercased(["Banana", "BANDANA", "cabana", "Straße"].map(|w| w.chars()));
+        vocab.train(10, 2);
+        assert!(vocab.tokens().iter().flatten().all(|c| !c.is_uppercase()));
+        let tokenizer = vocab.build().with_lowercase();
+
+        let word = "BaNaNa".chars().collect::<Vec<_>>();
+        let tokens = tokenizer.tokenize(&word);
+        assert_eq!(tokens.concat(), word);
+        let lower = "banana".chars().collect::<Vec<_>>();
+        assert_eq!(tokenizer.encode(&word), tokenizer.encode(&lower));
+
+        assert_eq!(lowercase(&'\u{1e9e}'), 'ß');
+        assert_eq!(lowercase(&'ß'), 'ß');
+        assert_eq!(lowercase(&'\u{130}'), '\u{130}');
+    }
+
+    #[test]
+    fn reader_split_on_delimiters() {
+        let vocab = Vocab::from_reader_split("a b\nc".as_bytes(), &[' ', '\n']).unwrap();
+        assert_eq!(vocab.words.len(), 3);
+        assert_eq!(vocab.required_base_alphabet().len(), 3);
+
+        let text = std::fs::read_to_string("data.txt")
+            .unwrap()
+            .replace('\n', " ");
+        let text = format!("{text}\t{text}  {text}");
+        let mut counted = Vocab::from_reader_split(text.as_bytes(), &[' ', '\t']).unwrap();
+        let mut listed = Vocab::new(text.split([' ', '\t']).map(|w| w.chars()));
+        assert!(counted.words.len() * 3 <= listed.words.len());
+        counted.train(100, 2);
+        listed.train(100, 2);
+        assert_eq!(counted.order, listed.order);
+        let frequencies =
+            |v: &Vocab<char>| v.merge_records().map(|r| r.frequency()).collect::<Vec<_>>();
+        assert_eq!(frequencies(&counted), frequencies(&listed));
+    }
+}